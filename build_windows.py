@@ -35,6 +35,7 @@ build_dir = op.join(root_dir, f"target/{TARGET_TRIPLE}/release")
 share_dir = op.join(root_dir, "share")
 
 shutil.copy(op.join(build_dir, "moonwatcher.exe"), output_dir)
+shutil.copy(op.join(build_dir, "moonwatch.exe"), output_dir)
 shutil.copy(op.join(share_dir, "default-config-windows.json"), op.join(output_dir, "config.json"))
 shutil.copy(op.join(share_dir, "install_windows.bat"), output_dir)
 
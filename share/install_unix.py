@@ -3,18 +3,17 @@ import os.path as op
 import os
 import shutil
 import subprocess
-import sys
 
 build_dir = op.abspath(op.dirname(__file__))
 install_dir = op.expanduser("~/.moonwatch-rs")
 
-print("Testing availability of dependencies (gnome-screensaver-command, xprintidle, xdotool)")
-for cmd in (["gnome-screensaver", "-h"], ["xprintidle", "-v"], ["xdotool", "-v"]):
+print("Testing availability of optional dependencies (xprintidle, xdotool)")
+print("These are only needed when neither sway/i3 IPC nor X11 with XScreenSaver extension is available")
+for cmd in (["xprintidle", "-v"], ["xdotool", "-v"]):
     try:
         subprocess.check_call(cmd)
-    except subprocess.CalledProcessError:
-        print("Warning -", cmd, "failed, please install it before using moonwatch-rs")
-        sys.exit(1)
+    except (OSError, subprocess.CalledProcessError):
+        print("Warning -", cmd, "failed, you may need to install it before using moonwatch-rs")
 
 print("Stopping moonwatch-rs service")
 rv = subprocess.call(["systemctl", "--user", "stop", "moonwatch-rs"])
@@ -26,6 +25,7 @@ if not os.path.exists(install_dir):
     os.makedirs(install_dir)
 
 shutil.copy(op.join(build_dir, "moonwatcher"), install_dir)
+shutil.copy(op.join(build_dir, "moonwatch"), install_dir)
 if op.exists(op.join(install_dir, "config.json")):
     print("config.json already exists, not copying default")
 else:
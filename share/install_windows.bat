@@ -8,6 +8,7 @@
 mkdir "%MOONWATCHDIR%"
 
 copy moonwatcher.exe "%MOONWATCHDIR%"
+copy moonwatch.exe "%MOONWATCHDIR%"
 
 @if exist "%MOONWATCHDIR%\config.json" (
     @echo config.json already exists, not copying default
@@ -0,0 +1,184 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use moonwatch_rs::store::{EventFilter, EventStore};
+use moonwatch_rs::watcher::core::{ActiveWindowEvent, Event, EventStatus};
+use moonwatch_rs::watcher::encryption;
+use anyhow::{bail, Result};
+use chrono::{DateTime, Datelike, Local, NaiveDate, TimeZone, Utc};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+const UNTAGGED: &str = "(untagged)";
+const ANONYMIZED: &str = "(anonymized)";
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+/// Moonwatch.rs analytics for event logs written by the `moonwatcher` daemon
+struct MoonwatchCli {
+    #[command(subcommand)]
+    command: MoonwatchCommand,
+}
+
+#[derive(Subcommand)]
+enum MoonwatchCommand {
+    /// Print time spent per tag or process, optionally broken down per day or week
+    Report(ReportArgs),
+}
+
+#[derive(Args)]
+struct ReportArgs {
+    #[arg(value_name = "OUTPUT_DIR", help = "directory with event logs (\"output_dir\" from config.json)")]
+    output_dir: PathBuf,
+
+    #[arg(long, value_enum, default_value_t = GroupBy::Tag, help = "what to sum time by")]
+    by: GroupBy,
+
+    #[arg(long, value_enum, help = "print separate summary for each day or week")]
+    per: Option<Period>,
+
+    #[arg(long, value_name = "YYYY-MM-DD", help = "only include events from this day on (local time)")]
+    from: Option<NaiveDate>,
+
+    #[arg(long, value_name = "YYYY-MM-DD", help = "only include events up to this day, inclusive (local time)")]
+    to: Option<NaiveDate>,
+
+    #[arg(long = "host", value_name = "HOSTNAME", help = "only include events from given host (can be given multiple times)")]
+    hosts: Vec<String>,
+
+    #[arg(long, help = "include events where the user was away from keyboard")]
+    include_afk: bool,
+
+    #[arg(long, value_name = "IDENTITY_FILE", help = "age identity file for reading encrypted event logs (can be given multiple times)")]
+    identity: Vec<PathBuf>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum GroupBy {
+    /// Sum time per tag; events with multiple tags count towards each of them
+    Tag,
+    /// Sum time per process executable name
+    Process,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Period {
+    Day,
+    Week,
+}
+
+#[derive(Default)]
+struct Summary {
+    total: Duration,
+    per_key: BTreeMap<String, Duration>,
+}
+
+impl ReportArgs {
+    fn filter(&self) -> EventFilter {
+        EventFilter {
+            from: self.from.map(start_of_day),
+            to: self.to.and_then(|tmp| tmp.succ_opt()).map(start_of_day),
+            hostnames: self.hosts.clone(),
+            usernames: vec![],
+        }
+    }
+
+    fn keys(&self, e: &ActiveWindowEvent) -> Vec<String> {
+        match self.by {
+            GroupBy::Tag if e.tags.is_empty() => vec![UNTAGGED.to_string()],
+            GroupBy::Tag => e.tags.iter().cloned().collect(),
+            GroupBy::Process if e.anonymize => vec![ANONYMIZED.to_string()],
+            GroupBy::Process => vec![e.process_path.file_name()
+                .map(|tmp| tmp.to_string_lossy().into_owned())
+                .unwrap_or_else(|| e.process_path.to_string_lossy().into_owned())],
+        }
+    }
+
+    fn period(&self, e: &ActiveWindowEvent) -> String {
+        let date = e.time.with_timezone(&Local).date_naive();
+        match self.per {
+            None => "Total".to_string(),
+            Some(Period::Day) => date.format("%Y-%m-%d %a").to_string(),
+            Some(Period::Week) => {
+                let week = date.iso_week();
+                let monday = date - chrono::Duration::days(date.weekday().num_days_from_monday() as i64);
+                format!("{}-W{:02} (from {})", week.year(), week.week(), monday.format("%Y-%m-%d"))
+            }
+        }
+    }
+}
+
+fn start_of_day(date: NaiveDate) -> DateTime<Utc> {
+    let midnight = date.and_hms_opt(0, 0, 0).unwrap();
+    match Local.from_local_datetime(&midnight).earliest() {
+        Some(tmp) => tmp.with_timezone(&Utc),
+        None => Utc.from_utc_datetime(&midnight),
+    }
+}
+
+fn format_duration(d: Duration) -> String {
+    let minutes = (d.as_secs_f64() / 60.0).round() as u64;
+    format!("{}h {:02}m", minutes / 60, minutes % 60)
+}
+
+fn report(args: &ReportArgs) -> Result<()> {
+    let mut identities = encryption::Identities::new();
+    for path in &args.identity {
+        identities.extend(encryption::read_identities(path)?);
+    }
+
+    let store = EventStore::open_with_identities(&args.output_dir, identities)?;
+    let mut summaries = BTreeMap::<String, Summary>::new();
+    let mut errors = 0;
+    for e in store.events(args.filter())? {
+        let e = match e {
+            Ok(Event::ActiveWindow(e)) => e,
+            // lock, afk and daemon events mark boundaries, time is only counted from window samples
+            Ok(Event::Session(_)) => continue,
+            Err(err) => {
+                eprintln!("Skipping unreadable events: {}", err);
+                errors += 1;
+                continue;
+            }
+        };
+        if e.status == EventStatus::Afk && !args.include_afk {
+            continue;
+        }
+
+        let summary = summaries.entry(args.period(&e)).or_default();
+        summary.total += e.duration;
+        for key in args.keys(&e) {
+            *summary.per_key.entry(key).or_default() += e.duration;
+        }
+    }
+
+    if summaries.is_empty() {
+        println!("No events found in {:?}", store.dir());
+    }
+
+    for (period, summary) in &summaries {
+        let mut rows: Vec<(&String, &Duration)> = summary.per_key.iter().collect();
+        rows.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        let width = rows.iter().map(|(key, _)| key.chars().count()).max().unwrap_or(0).max(5);
+
+        println!("{}", period);
+        for (key, d) in rows {
+            let share = 100.0 * d.as_secs_f64() / summary.total.as_secs_f64().max(1.0);
+            println!("  {:<width$}  {:>9}  {:>5.1}%", key, format_duration(*d), share, width = width);
+        }
+        println!("  {:<width$}  {:>9}", "total", format_duration(summary.total), width = width);
+        println!();
+    }
+
+    if errors > 0 {
+        bail!("some event logs could not be read ({} error(s))", errors);
+    }
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let cli = MoonwatchCli::parse();
+
+    match &cli.command {
+        MoonwatchCommand::Report(args) => report(args),
+    }
+}
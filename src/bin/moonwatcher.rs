@@ -1,16 +1,19 @@
 #![windows_subsystem = "windows"]
 
-use std::fs;
-use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Duration;
-use chrono;
 use moonwatch_rs::watcher;
-use moonwatch_rs::watcher::core::{ActiveWindowEvent, Desktop, MoonwatcherSignal};
+use moonwatch_rs::watcher::anonymize::{AnonymizeKey, AnonymizeMode};
+use moonwatch_rs::watcher::clock::{Clock, SampleTimer, SimulatedClock, SystemClock};
+use moonwatch_rs::watcher::core::{ActiveWindowEvent, DaemonInfo, Desktop, Event, EventStatus, MoonwatcherSignal, SessionEvent, SessionEventKind};
 use moonwatch_rs::watcher::config::Config;
+use moonwatch_rs::watcher::config_watcher::ConfigWatcher;
+use moonwatch_rs::watcher::journal::Journal;
+use moonwatch_rs::watcher::writer::{WriterConfig, WriterThread};
 use anyhow::Result;
-use sha1::{Sha1, Digest};
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use chrono::{DateTime, Utc};
 
 #[derive(Debug)]
 enum ActiveWindowEventResult {
@@ -18,7 +21,7 @@ enum ActiveWindowEventResult {
     Window { e: ActiveWindowEvent }
 }
 
-fn get_window_event(desktop: &dyn Desktop, duration: Duration) -> Result<ActiveWindowEventResult> {
+fn get_window_event(desktop: &dyn Desktop, clock: &dyn Clock, duration: Duration) -> Result<ActiveWindowEventResult> {
     if desktop.is_screen_locked() {
         Ok(ActiveWindowEventResult::DesktopLocked)
     } else {
@@ -27,149 +30,208 @@ fn get_window_event(desktop: &dyn Desktop, duration: Duration) -> Result<ActiveW
         let process_path = window.get_process_path()?;
         let window_title = window.get_title().unwrap_or_default();
 
-        let e = ActiveWindowEvent::new(idle_duration, window_title, process_path, duration);
+        let e = ActiveWindowEvent::new(clock, idle_duration, window_title, process_path, duration);
         Ok(ActiveWindowEventResult::Window { e })
     }
 }
 
-struct MoonwatcherWriter {
-    events_to_write: Vec<ActiveWindowEvent>
+#[derive(Parser)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+/// The Moonwatch.rs daemon
+struct MoonwatcherCli {
+    #[command(subcommand)]
+    command: Option<MoonwatcherCommand>,
+
+    #[arg(value_name = "CONFIG.JSON", help = "path to config.json file", required = true)]
+    config_path: Option<PathBuf>,
+
+    #[arg(long, value_name = "TIMELINE.JSON", help = "play back scripted desktop timeline instead of observing the real desktop")]
+    scripted_desktop: Option<PathBuf>,
+
+    #[arg(long, value_name = "START_TIME", help = "run on simulated clock starting at given RFC 3339 time, skipping over waits")]
+    simulated_clock: Option<DateTime<Utc>>,
 }
 
-impl MoonwatcherWriter {
-    pub fn new() -> MoonwatcherWriter {
-        MoonwatcherWriter {
-            events_to_write: vec![]
-        }
-    }
+#[derive(Subcommand)]
+enum MoonwatcherCommand {
+    /// Validate configuration file and its base configs without starting the daemon
+    CheckConfig {
+        #[arg(value_name = "CONFIG.JSON", help = "path to config.json file")]
+        config_path: PathBuf,
+    },
+}
 
-    pub fn push(&mut self, e: ActiveWindowEvent) {
-        self.events_to_write.push(e)
+fn check_config(config_path: &Path) -> Result<()> {
+    let config = Config::validate_file(config_path)?;
+
+    if config.uses_anonymize_key() && config.anonymize_key_path.exists() {
+        AnonymizeKey::load(config.anonymize_key_path.as_path())?;
     }
 
-    pub fn write(&mut self, config: &Config) -> Result<()> {
-        if self.events_to_write.is_empty() {
-            return Ok(());
-        }
+    println!("Configuration {:?} is OK ({} tag rules, {} ignore rules, {} anonymize rules, {} rewrite rules)",
+             config_path, config.tags.len(), config.ignore.len(), config.anonymize.len(), config.rewrite.len());
+    Ok(())
+}
 
-        // ensure output dir
-        if !config.output_dir.exists() {
-            println!("Creating output dir {:?}", config.output_dir);
-            fs::create_dir_all(&config.output_dir)?;
-        }
+fn read_config(config_path: &Path, cli: &MoonwatcherCli) -> Result<Config> {
+    let mut config = Config::from_file(config_path)?;
+    if cli.scripted_desktop.is_some() {
+        config.scripted_desktop = cli.scripted_desktop.clone();
+    }
+    Ok(config)
+}
 
-        // derive name for output file
-        let mut hasher = Sha1::new();
-        hasher.update(whoami::hostname());
-        hasher.update(whoami::username());
-        hasher.update(chrono::Utc::now().timestamp().to_le_bytes());
-        hasher.update(b"moonwatcher");
-        let hasher_result = hasher.finalize();
-        let filename = format!("{:02x}.jsonl", hasher_result);
-        let output_path = config.output_dir.join(filename);
-
-        // TODO consider writing .jsonl.gz instead
-        // TODO consider allowing output encryption
-
-        println!("Writing {} events to {:?}", self.events_to_write.len(), output_path);
-        let mut fp = fs::File::create(output_path)?;
-        while !self.events_to_write.is_empty() {
-            let e = self.events_to_write.pop().unwrap();
-            let line = e.to_json().dump();
-            fp.write(line.as_str().as_bytes())?;
-            fp.write(b"\n")?;
-        }
+fn load_anonymize_key(config: &Config) -> Result<Option<AnonymizeKey>> {
+    if config.uses_anonymize_key() {
+        Ok(Some(AnonymizeKey::load_or_create(config.anonymize_key_path.as_path())?))
+    } else {
+        Ok(None)
+    }
+}
 
-        Ok(())
+/// Short description of the event for the daemon's output, without window title and process path if `redact`.
+fn describe_window_event(e: &ActiveWindowEvent, redact: bool) -> String {
+    if redact {
+        format!("ActiveWindowEvent at {} ({:?}, {:?}, tags {:?})", e.time.to_rfc3339(), e.duration, e.status, e.tags)
+    } else {
+        format!("{:?}", e)
     }
 }
 
-#[derive(Parser)]
-#[command(author, version, about, long_about = None)]
-/// The Moonwatch.rs daemon
-struct MoonwatcherCli {
-    #[arg(value_name = "CONFIG.JSON", help = "path to config.json file")]
-    config_path: PathBuf,
+fn record_event(writer: &WriterThread, e: Event, redact_log: bool) {
+    match &e {
+        Event::ActiveWindow(w) => println!("Recording {}", describe_window_event(w, redact_log)),
+        _ => println!("Recording {:?}", e),
+    }
+    if let Err(e) = writer.push(e) {
+        println!("Failed to pass event to writer: {:?}", e);
+    }
+}
+
+fn record_session_event(writer: &WriterThread, clock: &dyn Clock, kind: SessionEventKind) {
+    record_event(writer, Event::Session(SessionEvent::new(clock, kind)), false);
+}
+
+fn daemon_info(config: &Config) -> DaemonInfo {
+    DaemonInfo { version: env!("CARGO_PKG_VERSION").to_string(), config_hash: config.hash.clone() }
+}
+
+fn get_started_desktop(config: &Config, clock: Arc<dyn Clock>) -> Result<Box<dyn Desktop>> {
+    let desktop = watcher::get_desktop(config, clock)?;
+    println!("Using desktop implementation: {}", desktop.implementation_name());
+    desktop.before_main_loop_start()?;
+    Ok(desktop)
+}
+
+/// A sample stands for at most this many sample periods, even if the previous sample was longer ago
+/// (eg. after the screen was locked and sampling slowed down, or the main loop was stuck writing).
+const MAX_SAMPLE_PERIODS: u32 = 2;
+
+/// Wait for changes to settle for this long before reloading changed config (eg. while it is being synced).
+const CONFIG_RELOAD_DEBOUNCE: Duration = Duration::from_secs(2);
+
+fn get_config_watcher(config_path: &Path, config: &Config) -> ConfigWatcher {
+    if !config.reload_on_change {
+        return ConfigWatcher::disabled();
+    }
+
+    let mut paths = vec![config_path.to_path_buf()];
+    paths.extend(config.base_config_paths.iter().cloned());
+    match ConfigWatcher::spawn(&paths, CONFIG_RELOAD_DEBOUNCE) {
+        Ok(config_watcher) => config_watcher,
+        Err(e) => {
+            println!("Failed to watch configuration files, changes will need manual reload: {:?}", e);
+            ConfigWatcher::disabled()
+        }
+    }
 }
 
 fn main() -> Result<()> {
     let cli = MoonwatcherCli::parse();
-    let config_path = cli.config_path;
+
+    if let Some(MoonwatcherCommand::CheckConfig { config_path }) = &cli.command {
+        return check_config(config_path);
+    }
 
     println!("--- Moonwatch ---");
+    let config_path = cli.config_path.as_deref().expect("config path is required by clap");
     println!("Configuration file: {:?}", config_path);
-    let mut config = Config::from_file(config_path.as_path())?;
+    let mut config = read_config(config_path, &cli)?;
     println!("Read configuration: {:?}", config);
 
-    let mut desktop = watcher::get_desktop(&config)?;
-    println!("Using desktop implementation: {}", desktop.implementation_name());
-    desktop.before_main_loop_start()?;
+    let clock: Arc<dyn Clock> = match cli.simulated_clock {
+        Some(start_time) => {
+            println!("Using simulated clock starting at {}", start_time.to_rfc3339());
+            Arc::new(SimulatedClock::new(start_time, true))
+        }
+        None => Arc::new(SystemClock)
+    };
 
-    let mut writer = MoonwatcherWriter::new();
+    let mut desktop = get_started_desktop(&config, clock.clone())?;
+    let mut anonymize_key = load_anonymize_key(&config)?;
+
+    let journal_path = config.state_dir.join("journal.jsonl");
+    let (journal, replayed_events) = match Journal::open(journal_path.as_path()) {
+        Ok((journal, replayed_events)) => (Some(journal), replayed_events),
+        Err(e) => {
+            println!("Failed to open journal {:?}, unwritten events will not survive a crash: {:?}", journal_path, e);
+            (None, vec![])
+        }
+    };
+    let writer = WriterThread::spawn(WriterConfig::from_config(&config), journal, replayed_events, clock.clone());
+    record_session_event(&writer, clock.as_ref(), SessionEventKind::DaemonStart(daemon_info(&config)));
 
     let signal_chan = watcher::get_signal_channel()?;
-    let mut writer_tick_chan = crossbeam_channel::tick(config.write_every);
+    let mut config_watcher = get_config_watcher(config_path, &config);
+    let mut writer_tick_chan = clock.tick(config.write_every);
     let mut sample_tick_slow = false;
-    let mut sample_tick_chan = crossbeam_channel::tick(config.sample_every);
-
-    // TODO do writing in separate thread to not stall sampling
+    let mut sample_tick_chan = clock.tick(config.sample_every);
+    let mut sample_timer = SampleTimer::new(clock.as_ref());
+    let mut locked = false;
+    let mut afk = false;
 
     loop {
-        crossbeam_channel::select! {
-            recv(signal_chan) -> sig => {
-                match sig? {
-                    MoonwatcherSignal::ReloadConfig => {
-                        println!("Reloading configuration file");
-                        match Config::from_file(config_path.as_path()) {
-                            Ok(new_config) => {
-                                println!("Read configuration: {:?}", new_config);
-
-                                // in the future, Desktop may depend on Config, so reload it as well
-                                match watcher::get_desktop(&new_config) {
-                                    Ok(new_desktop) => {
-                                        config = new_config;
-                                        desktop = new_desktop;
-                                        sample_tick_slow = false;
-                                        sample_tick_chan = crossbeam_channel::tick(config.sample_every);
-                                        writer_tick_chan = crossbeam_channel::tick(config.write_every);
-                                    }
-                                    Err(e) => {
-                                        println!("Failed to get desktop implementation, rolling back config update: {:?}", e);
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                println!("Failed to reload configuration: {:?}", e);
-                            }
-                        }
-                    }
-                    MoonwatcherSignal::Terminate => {
-                        println!("Writing data");
-                        match writer.write(&config) {
-                            Ok(_) => { println!("Wrote successfully"); }
-                            Err(e) => { println!("Failed to write at exit, data will be lost!! Error: {:?}", e) }
-                        }
-
-                        println!("Terminating due to OS signal");
-                        break;
-                    }
-                }
-            }
+        clock.before_wait();
+        let sig = crossbeam_channel::select! {
+            recv(signal_chan) -> sig => sig?,
+            recv(config_watcher.receiver()) -> sig => sig?,
             recv(writer_tick_chan) -> _ => {
-                println!("Writing data");
-                match writer.write(&config) {
-                    Ok(_) => { println!("Wrote successfully"); }
-                    Err(e) => { println!("Error when writing data (will try later): {:?}", e) }
+                if let Err(e) = writer.write() {
+                    println!("Failed to request write: {:?}", e);
                 }
+                continue;
             }
             recv(sample_tick_chan) -> _ => {
-                let res = get_window_event(desktop.as_ref(), config.sample_every); // this is not quite accurate w/ sample_tick_slow
+                if desktop.is_finished() {
+                    println!("Terminating, desktop {} is finished", desktop.implementation_name());
+                    break;
+                }
+
+                let interval = sample_timer.next(clock.as_ref(), MAX_SAMPLE_PERIODS*config.sample_every);
+                if let Some(since) = interval.suspended_since {
+                    println!("Machine was suspended after {}, not counting the gap", since.to_rfc3339());
+                    let mut e = SessionEvent::new(clock.as_ref(), SessionEventKind::Suspend);
+                    e.time = since;
+                    record_event(&writer, Event::Session(e), false);
+                    record_session_event(&writer, clock.as_ref(), SessionEventKind::Resume);
+                }
+
+                let res = get_window_event(desktop.as_ref(), clock.as_ref(), interval.duration);
                 match res {
                     Ok(ActiveWindowEventResult::DesktopLocked) => {
+                        if !locked {
+                            locked = true;
+                            // the user is not counted as away while locked, so that afk and lock spans do not overlap
+                            if afk {
+                                afk = false;
+                                record_session_event(&writer, clock.as_ref(), SessionEventKind::AfkEnd);
+                            }
+                            record_session_event(&writer, clock.as_ref(), SessionEventKind::Lock);
+                        }
                         if !sample_tick_slow {
                             println!("slowing down sample rate");
                             sample_tick_slow = true;
-                            sample_tick_chan = crossbeam_channel::tick(10*config.sample_every);
+                            sample_tick_chan = clock.tick(10*config.sample_every);
                         }
                     }
                     Ok(ActiveWindowEventResult::Window { mut e }) => {
@@ -177,38 +239,123 @@ fn main() -> Result<()> {
                         if sample_tick_slow {
                             println!("resetting sample rate");
                             sample_tick_slow = false;
-                            sample_tick_chan = crossbeam_channel::tick(config.sample_every);
+                            sample_tick_chan = clock.tick(config.sample_every);
+                        }
+
+                        if locked {
+                            locked = false;
+                            record_session_event(&writer, clock.as_ref(), SessionEventKind::Unlock);
+                            // the time since the last (locked) sample was spent locked, at least partly; count at most
+                            // one sample period, the most the user can have been active since the unlock
+                            e.duration = e.duration.min(config.sample_every);
+                        }
+
+                        if config.afk_after.is_some_and(|tmp| e.idle_for >= tmp) {
+                            e.status = EventStatus::Afk;
+                        }
+                        if afk != (e.status == EventStatus::Afk) {
+                            afk = e.status == EventStatus::Afk;
+                            let kind = if afk { SessionEventKind::AfkStart } else { SessionEventKind::AfkEnd };
+                            record_session_event(&writer, clock.as_ref(), kind);
                         }
 
                         // do we want to skip this event?
                         let should_ignore = config.ignore.iter().any(|m| m.matches(&e));
                         if should_ignore {
-                            println!("Ignoring {:?}", e);
+                            println!("Ignoring {}", describe_window_event(&e, config.redact_log()));
                             continue
                         };
 
                         // fill in event according to config
-                        e.anonymize = config.anonymize.iter().any(|m| m.matches(&e));
+                        let anonymize_mode = config.anonymize_mode(&e);
+                        e.anonymize = anonymize_mode == Some(AnonymizeMode::Redact);
                         for t in &config.tags {
                             if t.matcher.matches(&e) && !e.tags.contains(&t.tag) {
                                 e.tags.push_back(t.tag.clone())
                             }
                         }
 
-                        println!("Recording {:?}", e);
-                        writer.push(e);
+                        for r in &config.rewrite {
+                            r.apply(&mut e);
+                        }
+
+                        if anonymize_mode == Some(AnonymizeMode::Hash) {
+                            match &anonymize_key {
+                                Some(key) => key.hash_event(&mut e),
+                                None => e.anonymize = true,
+                            }
+                        }
+
+                        record_event(&writer, Event::ActiveWindow(e), config.redact_log());
                     }
                     _ => {
                         if !sample_tick_slow {
                             println!("slowing down sample rate");
                             sample_tick_slow = true;
-                            sample_tick_chan = crossbeam_channel::tick(10*config.sample_every);
+                            sample_tick_chan = clock.tick(10*config.sample_every);
                         }
                     }
                 }
+
+                continue;
+            }
+        };
+
+        match sig {
+            MoonwatcherSignal::ReloadConfig => {
+                println!("Reloading configuration file");
+                match read_config(config_path, &cli) {
+                    Ok(new_config) => {
+                        println!("Read configuration: {:?}", new_config);
+
+                        // a new desktop would start over (eg. a new logind connection, or the scripted timeline
+                        // from its first step), so only replace it if its settings changed
+                        let new_desktop = if new_config.scripted_desktop != config.scripted_desktop {
+                            get_started_desktop(&new_config, clock.clone()).map(Some)
+                        } else {
+                            Ok(None)
+                        };
+                        match (new_desktop, load_anonymize_key(&new_config)) {
+                            (Ok(new_desktop), Ok(new_anonymize_key)) => {
+                                config = new_config;
+                                record_session_event(&writer, clock.as_ref(), SessionEventKind::ConfigReload(daemon_info(&config)));
+                                if let Some(new_desktop) = new_desktop {
+                                    desktop = new_desktop;
+                                }
+                                anonymize_key = new_anonymize_key;
+                                config_watcher = get_config_watcher(config_path, &config);
+                                if let Err(e) = writer.update_config(WriterConfig::from_config(&config)) {
+                                    println!("Failed to update writer configuration: {:?}", e);
+                                }
+                                sample_tick_slow = false;
+                                sample_tick_chan = clock.tick(config.sample_every);
+                                writer_tick_chan = clock.tick(config.write_every);
+                            }
+                            (Err(e), _) => {
+                                println!("Failed to get desktop implementation, rolling back config update: {:?}", e);
+                            }
+                            (_, Err(e)) => {
+                                println!("Failed to load anonymize key, rolling back config update: {:?}", e);
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        println!("Failed to reload configuration: {:?}", e);
+                    }
+                }
+            }
+            MoonwatcherSignal::Terminate => {
+                println!("Terminating due to OS signal");
+                break;
             }
         }
     }
 
+    record_session_event(&writer, clock.as_ref(), SessionEventKind::DaemonStop(daemon_info(&config)));
+    match writer.finish() {
+        Ok(_) => {}
+        Err(e) => { println!("Failed to write at exit!! Error: {:?}", e) }
+    }
+
     Ok(())
 }
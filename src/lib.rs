@@ -1 +1,5 @@
 pub mod watcher;
+pub mod store;
+
+#[cfg(test)]
+mod test_util;
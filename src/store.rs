@@ -0,0 +1,373 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::fs;
+use std::io::{BufRead, Lines};
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Utc};
+use crate::watcher::compression;
+use crate::watcher::core::Event;
+use crate::watcher::encryption::{self, Identities};
+
+/// Directory with event logs (`"output_dir"` of the daemon configuration).
+///
+/// The directory contains many chunk files (one per write), possibly from several machines, compressed
+/// and/or encrypted. They are read back as `Event`s (window samples as well as session events), accepting
+/// all schema versions supported by `Event::from_json`.
+pub struct EventStore {
+    dir: PathBuf,
+    identities: Identities,
+}
+
+/// Which events to read, empty filter matches everything.
+#[derive(Debug, Clone, Default)]
+pub struct EventFilter {
+    /// Only events that end at this time or later, so that spans of merged samples that started
+    /// earlier are included.
+    pub from: Option<DateTime<Utc>>,
+    /// Only events that start before this time.
+    pub to: Option<DateTime<Utc>>,
+    /// Only events from one of these hosts, empty means any host.
+    pub hostnames: Vec<String>,
+    /// Only events of one of these users, empty means any user.
+    pub usernames: Vec<String>,
+}
+
+impl EventFilter {
+    pub fn matches(&self, e: &Event) -> bool {
+        if self.from.is_some_and(|tmp| e.end() < tmp) || self.to.is_some_and(|tmp| e.time() >= tmp) {
+            return false;
+        }
+        if !self.hostnames.is_empty() && !self.hostnames.iter().any(|tmp| tmp == e.hostname()) {
+            return false;
+        }
+        self.usernames.is_empty() || self.usernames.iter().any(|tmp| tmp == e.username())
+    }
+}
+
+impl EventStore {
+    pub fn open(dir: &Path) -> Result<EventStore> {
+        EventStore::open_with_identities(dir, Identities::new())
+    }
+
+    /// Open store with private keys for reading encrypted chunks (see `encryption::read_identities`).
+    pub fn open_with_identities(dir: &Path, identities: Identities) -> Result<EventStore> {
+        if !dir.is_dir() {
+            bail!("{:?} is not a directory", dir);
+        }
+        Ok(EventStore { dir: dir.to_path_buf(), identities })
+    }
+
+    pub fn dir(&self) -> &Path {
+        self.dir.as_path()
+    }
+
+    /// Paths of all chunk files in the store, sorted by name.
+    pub fn chunk_paths(&self) -> Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.is_file() && compression::is_event_log_file(&path) {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// Read all events from a single chunk file, in the order they were written.
+    pub fn read_chunk(&self, path: &Path) -> Result<Vec<Event>> {
+        Event::read_jsonl_with_identities(path, &self.identities)
+    }
+
+    /// Stream events matching the filter from all chunk files, sorted by time.
+    ///
+    /// Chunks are first scanned for the time of their first event, and each chunk is only opened once
+    /// its events are due, so that just the chunks that overlap in time (eg. from several machines) are
+    /// read at once, line by line. Chunks written by older versions of the daemon are newest-first
+    /// (they are told apart by their missing `schema_version`); these are read whole and sorted.
+    /// A chunk that cannot be read produces an error item, with the events before the unreadable part
+    /// still included, and the iteration goes on with the rest.
+    pub fn events(&self, filter: EventFilter) -> Result<EventIter<'_>> {
+        let mut iter = EventIter {
+            store: self,
+            filter,
+            pending: Vec::new(),
+            chunks: Vec::new(),
+            heap: BinaryHeap::new(),
+            errors: VecDeque::new(),
+        };
+
+        for path in self.chunk_paths()? {
+            let start = match ChunkReader::open(&path, &self.identities).map(|mut reader| reader.start_time()) {
+                Ok(Some(start)) => start,
+                Ok(None) => continue,
+                // opened first, so that the error is reported right away
+                Err(_) => DateTime::<Utc>::MIN_UTC,
+            };
+            if iter.filter.to.is_none_or(|to| start < to) {
+                iter.pending.push((start, path));
+            }
+        }
+        iter.pending.sort_by(|a, b| b.cmp(a));
+
+        Ok(iter)
+    }
+}
+
+/// Reads events of a chunk file line by line.
+struct ChunkReader {
+    path: PathBuf,
+    lines: Lines<Box<dyn BufRead>>,
+    line_number: usize,
+}
+
+impl ChunkReader {
+    fn open(path: &Path, identities: &Identities) -> Result<ChunkReader> {
+        let lines = encryption::open_reader(path, identities)?.lines();
+        Ok(ChunkReader { path: path.to_path_buf(), lines, line_number: 0 })
+    }
+
+    /// Next event, and whether it comes from a newest-first legacy chunk. Reading stops at the first error.
+    fn next_event(&mut self) -> Option<Result<(Event, bool)>> {
+        for line in self.lines.by_ref() {
+            self.line_number += 1;
+            let line = match line {
+                Ok(line) => line,
+                Err(err) => return Some(Err(anyhow!("{:?}, line {}: {}", self.path, self.line_number, err))),
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let res = json::parse(&line).map_err(anyhow::Error::from)
+                .and_then(|val| Ok((Event::from_json(&val)?, val["schema_version"].is_null())))
+                .map_err(|err| anyhow!("{:?}, line {}: {}", self.path, self.line_number, err));
+            return Some(res);
+        }
+        None
+    }
+
+    /// Time of the earliest event, or None if there are no readable events.
+    fn start_time(&mut self) -> Option<DateTime<Utc>> {
+        let (first, legacy) = self.next_event()?.ok()?;
+        if !legacy {
+            return Some(first.time());
+        }
+        let mut start = first.time();
+        while let Some(Ok((e, _))) = self.next_event() {
+            start = start.min(e.time());
+        }
+        Some(start)
+    }
+}
+
+/// Events of an open chunk that are not returned yet.
+struct OpenChunk {
+    /// Next event, its time is in `EventIter::heap`.
+    next: Event,
+    rest: ChunkEvents,
+}
+
+enum ChunkEvents {
+    Reader(ChunkReader),
+    /// Matching events of a legacy chunk, sorted by time.
+    Sorted(std::vec::IntoIter<Event>),
+}
+
+/// Iterator over events of `EventStore`, see `EventStore::events`.
+pub struct EventIter<'a> {
+    store: &'a EventStore,
+    filter: EventFilter,
+    /// Chunks that are not opened yet and the time of their first event, latest first.
+    pending: Vec<(DateTime<Utc>, PathBuf)>,
+    /// Chunks that were opened, None once exhausted.
+    chunks: Vec<Option<OpenChunk>>,
+    /// Time of the next event of each open chunk that is not exhausted yet.
+    heap: BinaryHeap<Reverse<(DateTime<Utc>, usize)>>,
+    errors: VecDeque<anyhow::Error>,
+}
+
+impl EventIter<'_> {
+    fn open_chunk(&mut self, path: &Path) {
+        let mut reader = match ChunkReader::open(path, &self.store.identities) {
+            Ok(reader) => reader,
+            Err(err) => {
+                self.errors.push_back(err);
+                return;
+            }
+        };
+
+        let (first, legacy) = match reader.next_event() {
+            Some(Ok(tmp)) => tmp,
+            Some(Err(err)) => {
+                self.errors.push_back(err);
+                return;
+            }
+            None => return,
+        };
+
+        let mut rest = if legacy {
+            let mut events = vec![first];
+            while let Some(res) = reader.next_event() {
+                match res {
+                    Ok((e, _)) => events.push(e),
+                    Err(err) => self.errors.push_back(err),
+                }
+            }
+            events.retain(|e| self.filter.matches(e));
+            events.sort_by_key(|e| e.time());
+            ChunkEvents::Sorted(events.into_iter())
+        } else if self.filter.matches(&first) {
+            return self.push_chunk(OpenChunk { next: first, rest: ChunkEvents::Reader(reader) });
+        } else {
+            ChunkEvents::Reader(reader)
+        };
+        if let Some(next) = self.next_matching(&mut rest) {
+            self.push_chunk(OpenChunk { next, rest });
+        }
+    }
+
+    fn push_chunk(&mut self, chunk: OpenChunk) {
+        self.heap.push(Reverse((chunk.next.time(), self.chunks.len())));
+        self.chunks.push(Some(chunk));
+    }
+
+    /// Next event of the chunk that matches the filter.
+    fn next_matching(&mut self, events: &mut ChunkEvents) -> Option<Event> {
+        match events {
+            ChunkEvents::Sorted(events) => events.next(),
+            ChunkEvents::Reader(reader) => loop {
+                match reader.next_event()? {
+                    Ok((e, _)) if self.filter.matches(&e) => return Some(e),
+                    // sorted by time, so nothing later can match
+                    Ok((e, _)) if self.filter.to.is_some_and(|to| e.time() >= to) => return None,
+                    Ok(_) => continue,
+                    Err(err) => {
+                        self.errors.push_back(err);
+                        return None;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for EventIter<'_> {
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(err) = self.errors.pop_front() {
+                return Some(Err(err));
+            }
+
+            // open the next chunk once it may have the next event
+            let next_time = self.heap.peek().map(|Reverse((time, _))| *time);
+            if self.pending.last().is_some_and(|(start, _)| next_time.is_none_or(|tmp| *start <= tmp)) {
+                let (_, path) = self.pending.pop().unwrap();
+                self.open_chunk(&path);
+                continue;
+            }
+
+            let Reverse((_, i)) = self.heap.pop()?;
+            let OpenChunk { next: e, mut rest } = self.chunks[i].take()?;
+            if let Some(next) = self.next_matching(&mut rest) {
+                self.heap.push(Reverse((next.time(), i)));
+                self.chunks[i] = Some(OpenChunk { next, rest });
+            }
+            return Some(Ok(e));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::test_util::{start_time, TestDir};
+    use crate::watcher::clock::SimulatedClock;
+    use crate::watcher::compression::Compression;
+    use crate::watcher::core::ActiveWindowEvent;
+
+    fn time(minute: u32) -> DateTime<Utc> {
+        start_time() + chrono::Duration::minutes(minute.into())
+    }
+
+    /// JSON line of a window sample titled with its minute, eg. `"08:03"`.
+    fn line(minute: u32, hostname: &str) -> String {
+        let clock = SimulatedClock::new(time(minute), false);
+        let mut e = ActiveWindowEvent::new(&clock, Duration::ZERO, format!("08:{:02}", minute),
+                                           PathBuf::from("/usr/bin/code"), Duration::from_secs(60));
+        e.hostname = hostname.to_string();
+        e.to_json().dump()
+    }
+
+    fn titles(store: &EventStore, filter: EventFilter) -> (Vec<String>, usize) {
+        let mut titles = Vec::new();
+        let mut errors = 0;
+        for e in store.events(filter).unwrap() {
+            match e {
+                Ok(Event::ActiveWindow(e)) => titles.push(e.window_title),
+                Ok(Event::Session(e)) => panic!("unexpected {:?}", e),
+                Err(_) => errors += 1,
+            }
+        }
+        (titles, errors)
+    }
+
+    #[test]
+    fn chunks_are_merged_by_time() {
+        let dir = TestDir::new("store");
+
+        // written newest-first and without schema_version, like chunks of old versions of the daemon
+        let legacy_line = |minute: u32| {
+            let mut legacy = json::parse(&line(minute, "laptop")).unwrap();
+            for key in ["schema_version", "end", "status"] {
+                legacy.remove(key);
+            }
+            legacy.dump()
+        };
+        let legacy_chunk = [legacy_line(5), legacy_line(3), legacy_line(1)].join("\n");
+        dir.write("a.jsonl", legacy_chunk);
+
+        let chunk = [line(2, "laptop"), line(4, "desktop"), line(6, "laptop")].join("\n") + "\n";
+        dir.write("b.jsonl.gz", Compression::Gzip.compress(chunk.as_bytes()).unwrap());
+
+        let broken_chunk = [line(0, "laptop"), "{\"type\": ".to_string(), line(7, "laptop")].join("\n");
+        dir.write("c.jsonl", broken_chunk);
+        dir.write("d.txt", "not an event log");
+
+        let store = EventStore::open(dir.path()).unwrap();
+        let all = ["08:00", "08:01", "08:02", "08:03", "08:04", "08:05", "08:06"];
+        assert_eq!(titles(&store, EventFilter::default()), (all.map(String::from).to_vec(), 1));
+
+        let filter = EventFilter { from: Some(time(2)), to: Some(time(5)), ..Default::default() };
+        assert_eq!(titles(&store, filter), (vec!["08:02".to_string(), "08:03".to_string(), "08:04".to_string()], 1));
+
+        let filter = EventFilter { to: Some(time(2)), ..Default::default() };
+        assert_eq!(titles(&store, filter).0, vec!["08:00", "08:01"]);
+
+        let filter = EventFilter { from: Some(time(5)), hostnames: vec!["laptop".to_string()], ..Default::default() };
+        assert_eq!(titles(&store, filter).0, vec!["08:05", "08:06"]);
+    }
+
+    #[test]
+    fn spans_overlapping_time_range_are_included() {
+        let dir = TestDir::new("store-spans");
+        let clock = SimulatedClock::new(time(1), false);
+        let mut span = ActiveWindowEvent::new(&clock, Duration::ZERO, "08:01".to_string(),
+                                              PathBuf::from("/usr/bin/code"), Duration::from_secs(600));
+        span.end = time(10);
+        dir.write("a.jsonl", [span.to_json().dump(), line(11, "laptop")].join("\n"));
+
+        let store = EventStore::open(dir.path()).unwrap();
+        let filter = EventFilter { from: Some(time(5)), ..Default::default() };
+        assert_eq!(titles(&store, filter).0, vec!["08:01", "08:11"]);
+        let filter = EventFilter { from: Some(time(10)), to: Some(time(11)), ..Default::default() };
+        assert_eq!(titles(&store, filter).0, vec!["08:01"]);
+        let filter = EventFilter { from: Some(time(11)), ..Default::default() };
+        assert_eq!(titles(&store, filter).0, vec!["08:11"]);
+        let filter = EventFilter { to: Some(time(1)), ..Default::default() };
+        assert!(titles(&store, filter).0.is_empty());
+    }
+}
@@ -0,0 +1,45 @@
+//! Fixtures shared by unit tests (integration tests have their own in `tests/common`).
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, Utc};
+
+/// Fresh directory for one test, removed when dropped.
+pub struct TestDir {
+    path: PathBuf,
+}
+
+impl TestDir {
+    /// `name` must be unique among tests, as tests run in parallel.
+    pub fn new(name: &str) -> TestDir {
+        let path = std::env::temp_dir().join(format!("moonwatch-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TestDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.path.join(name)
+    }
+
+    pub fn write(&self, name: &str, contents: impl AsRef<[u8]>) -> PathBuf {
+        let path = self.path.join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Fixed time the simulated clock of tests starts at, 2024-01-01T08:00:00Z.
+pub fn start_time() -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339("2024-01-01T08:00:00Z").unwrap().with_timezone(&Utc)
+}
@@ -0,0 +1,173 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use anyhow::{anyhow, bail, Context, Result};
+use crate::watcher::core::ActiveWindowEvent;
+
+/// Prefix of window titles and process paths replaced by `AnonymizeMode::Hash`.
+pub const HASH_PREFIX: &str = "hmac-sha256:";
+
+const KEY_LENGTH: usize = 32;
+
+/// How events matched by an anonymize rule are recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AnonymizeMode {
+    /// Window title and process path are replaced by HMAC under local secret key, so that
+    /// events from the same window can still be grouped together.
+    Hash,
+    /// Window title and process path are written as null.
+    Redact,
+}
+
+impl AnonymizeMode {
+    pub fn parse(s: &str) -> Result<AnonymizeMode> {
+        match s {
+            "hash" => Ok(AnonymizeMode::Hash),
+            "redact" => Ok(AnonymizeMode::Redact),
+            _ => bail!("unknown anonymize mode {:?}, expected \"hash\" or \"redact\"", s),
+        }
+    }
+}
+
+/// Secret key for `AnonymizeMode::Hash`.
+///
+/// The key never leaves the machine; events hashed under the same key (eg. copied to other machines)
+/// can be compared with each other, but not reversed without it.
+pub struct AnonymizeKey {
+    path: PathBuf,
+    key: [u8; KEY_LENGTH],
+}
+
+impl AnonymizeKey {
+    /// Read key from file, or generate a new random key and save it there if the file does not exist.
+    pub fn load_or_create(path: &Path) -> Result<AnonymizeKey> {
+        if path.exists() {
+            AnonymizeKey::load(path)
+        } else {
+            println!("Generating new anonymize key {:?}", path);
+            AnonymizeKey::create(path)
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<AnonymizeKey> {
+        let data = fs::read_to_string(path).with_context(|| format!("cannot read anonymize key {:?}", path))?;
+        let data = data.trim();
+        if data.len() != 2 * KEY_LENGTH || !data.bytes().all(|c| c.is_ascii_hexdigit()) {
+            bail!("anonymize key {:?} must be {} hex digits", path, 2 * KEY_LENGTH);
+        }
+
+        let mut key = [0u8; KEY_LENGTH];
+        for (i, b) in key.iter_mut().enumerate() {
+            // all ASCII, so slicing stays on char boundaries
+            *b = u8::from_str_radix(&data[2 * i..2 * i + 2], 16)?;
+        }
+        Ok(AnonymizeKey { path: path.to_path_buf(), key })
+    }
+
+    fn create(path: &Path) -> Result<AnonymizeKey> {
+        let mut key = [0u8; KEY_LENGTH];
+        getrandom::getrandom(&mut key).map_err(|e| anyhow!("cannot generate anonymize key: {}", e))?;
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(path).with_context(|| format!("cannot create anonymize key {:?}", path))?;
+        file.write_all(to_hex(&key).as_bytes())?;
+        file.write_all(b"\n")?;
+        file.sync_all()?;
+
+        Ok(AnonymizeKey { path: path.to_path_buf(), key })
+    }
+
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    /// Keyed hash of `data`, as `HASH_PREFIX` followed by hex digest.
+    pub fn hash(&self, data: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(data.as_bytes());
+        format!("{}{}", HASH_PREFIX, to_hex(&mac.finalize().into_bytes()))
+    }
+
+    /// Replace window title and process path of the event with their keyed hashes.
+    pub fn hash_event(&self, e: &mut ActiveWindowEvent) {
+        e.window_title = self.hash(e.window_title.as_str());
+        e.process_path = PathBuf::from(self.hash(e.process_path.to_str().unwrap_or("")));
+    }
+}
+
+impl std::fmt::Debug for AnonymizeKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AnonymizeKey").field("path", &self.path).finish_non_exhaustive()
+    }
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::test_util::{start_time, TestDir};
+    use crate::watcher::clock::SimulatedClock;
+
+    const KEY_HEX: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    #[test]
+    fn key_is_created_once_and_loaded() {
+        let dir = TestDir::new("anonymize-key");
+        let path = dir.join("state").join("anonymize.key");
+        let created = AnonymizeKey::load_or_create(&path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        let loaded = AnonymizeKey::load_or_create(&path).unwrap();
+        assert_eq!(loaded.key, created.key);
+        assert_eq!(loaded.path(), path);
+    }
+
+    #[test]
+    fn invalid_keys_are_errors() {
+        let dir = TestDir::new("anonymize-invalid-key");
+        for data in ["", "0001", &KEY_HEX.replace('0', "g"), &format!("€{}", "a".repeat(61)), &format!("+f{}", &KEY_HEX[2..])] {
+            let path = dir.write("anonymize.key", data);
+            let error = AnonymizeKey::load(&path).unwrap_err().to_string();
+            assert!(error.contains("must be 64 hex digits"), "{:?}: {}", data, error);
+        }
+        assert!(AnonymizeKey::load(&dir.join("missing.key")).unwrap_err().to_string().contains("cannot read anonymize key"));
+    }
+
+    #[test]
+    fn hash_is_hmac_under_key() {
+        let dir = TestDir::new("anonymize-hash");
+        let key = AnonymizeKey::load(&dir.write("anonymize.key", format!("{}\n", KEY_HEX))).unwrap();
+        assert_eq!(key.hash("Inbox"), "hmac-sha256:fc91870b06dc95cadd8a3a13e1184d76c772fcfdf97d60d02809db88fd64d338");
+
+        let clock = SimulatedClock::new(start_time(), false);
+        let mut e = ActiveWindowEvent::new(&clock, Duration::from_secs(3), "Inbox".to_string(),
+                                           PathBuf::from("/usr/bin/thunderbird"), Duration::from_secs(15));
+        e.tags.push_back("mail".to_string());
+        e.anonymize = true;
+        let mut expected = e.clone();
+        expected.window_title = key.hash("Inbox");
+        expected.process_path = PathBuf::from("hmac-sha256:7303f97b394a5c9fe75d32cc5b446abc5671e69bf5555b1e49a12cc113808e29");
+
+        key.hash_event(&mut e);
+        assert_eq!(e.to_json(), expected.to_json());
+    }
+}
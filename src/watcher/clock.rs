@@ -0,0 +1,426 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+
+/// Source of time for the daemon: timestamps, monotonic time and periodic ticks.
+pub trait Clock: Send + Sync {
+    /// Current wall-clock time.
+    fn now(&self) -> DateTime<Utc>;
+    /// Current monotonic time.
+    fn instant(&self) -> Instant;
+    /// Periodic ticker, like `crossbeam_channel::tick`.
+    fn tick(&self, period: Duration) -> Receiver<Instant>;
+    /// Called by the main loop right before it blocks waiting for ticks.
+    fn before_wait(&self) {}
+}
+
+/// The real system clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn tick(&self, period: Duration) -> Receiver<Instant> {
+        crossbeam_channel::tick(period)
+    }
+}
+
+/// Wall-clock time may run ahead of monotonic time by this much without it being taken for a suspend
+/// (eg. when the system clock is adjusted).
+const SUSPEND_DETECTION_SLACK: Duration = Duration::from_secs(10);
+
+/// Measures how much time each sample of the main loop stands for.
+///
+/// Ticks don't arrive exactly every sample period: they are slowed down while the screen is locked,
+/// dropped while the main loop is busy and stopped while the machine is suspended. Instead of assuming
+/// the nominal period, each sample gets the monotonic time since the previous one, capped at a maximum.
+///
+/// The monotonic clock stops while the machine is suspended (on Linux and macOS), so a suspend shows up
+/// as wall-clock time running ahead of it; the suspended time is then not counted at all. Where the monotonic
+/// clock keeps running, the gap is only cut short by the cap.
+pub struct SampleTimer {
+    last_instant: Instant,
+    last_time: DateTime<Utc>,
+}
+
+/// Time since the previous sample, see `SampleTimer::next`.
+#[derive(Debug, Clone, Copy)]
+pub struct SampleInterval {
+    /// Monotonic time since the previous sample, at most the maximum passed to `SampleTimer::next`.
+    pub duration: Duration,
+    /// Wall-clock time of the previous sample, if the machine was suspended since then.
+    pub suspended_since: Option<DateTime<Utc>>,
+}
+
+impl SampleTimer {
+    /// Start measuring from now, eg. from the start of the main loop.
+    pub fn new(clock: &dyn Clock) -> SampleTimer {
+        SampleTimer { last_instant: clock.instant(), last_time: clock.now() }
+    }
+
+    /// Take a sample: return the time since the previous one (or since the timer was created),
+    /// capped at `max_duration`.
+    pub fn next(&mut self, clock: &dyn Clock, max_duration: Duration) -> SampleInterval {
+        let instant = clock.instant();
+        let time = clock.now();
+
+        let elapsed = instant.saturating_duration_since(self.last_instant);
+        let wall_elapsed = (time - self.last_time).to_std().unwrap_or(Duration::ZERO);
+        let suspended_since = if wall_elapsed > elapsed + SUSPEND_DETECTION_SLACK {
+            Some(self.last_time)
+        } else {
+            None
+        };
+
+        self.last_instant = instant;
+        self.last_time = time;
+        SampleInterval { duration: elapsed.min(max_duration), suspended_since }
+    }
+}
+
+/// Simulated clock where time only moves when asked to.
+///
+/// Time is moved either explicitly by `advance()`, or, with `auto_advance`, by jumping
+/// to the next due tick whenever the main loop is about to wait (see `Clock::before_wait`).
+/// The latter lets the daemon run through hours of simulated time instantly.
+///
+/// Like `crossbeam_channel::tick`, each ticker holds at most one pending tick; ticks
+/// that cannot be delivered are dropped.
+pub struct SimulatedClock {
+    auto_advance: bool,
+    state: Mutex<SimulatedClockState>,
+}
+
+struct SimulatedClockState {
+    start_time: DateTime<Utc>,
+    start_instant: Instant,
+    elapsed: Duration,
+    tickers: Vec<SimulatedTicker>,
+}
+
+struct SimulatedTicker {
+    period: Duration,
+    next_due: Duration,
+    sender: Sender<Instant>,
+}
+
+impl SimulatedClock {
+    pub fn new(start_time: DateTime<Utc>, auto_advance: bool) -> SimulatedClock {
+        SimulatedClock {
+            auto_advance,
+            state: Mutex::new(SimulatedClockState {
+                start_time,
+                start_instant: Instant::now(),
+                elapsed: Duration::ZERO,
+                tickers: Vec::new(),
+            })
+        }
+    }
+
+    /// Move time forward by `duration`, firing all tickers that become due.
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        let target = state.elapsed + duration;
+
+        while let Some(next_due) = state.next_due() {
+            if next_due > target {
+                break;
+            }
+            state.elapsed = next_due;
+            state.fire_due_tickers();
+        }
+
+        state.elapsed = target;
+    }
+
+    /// Move time forward to the earliest due ticker and fire it. Returns false if there are no tickers.
+    pub fn advance_to_next_tick(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        // tickers with no receiver are only dropped when they fire, so keep going until a tick is delivered
+        while let Some(next_due) = state.next_due() {
+            state.elapsed = state.elapsed.max(next_due);
+            if state.fire_due_tickers() {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Move wall-clock time by `offset` without moving monotonic time, as a suspend (forward)
+    /// or an adjustment of the system clock (either way) would.
+    pub fn shift_wall_clock(&self, offset: chrono::Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.start_time += offset;
+    }
+
+    /// Total simulated time since the clock was created.
+    pub fn elapsed(&self) -> Duration {
+        self.state.lock().unwrap().elapsed
+    }
+}
+
+impl SimulatedClockState {
+    fn next_due(&self) -> Option<Duration> {
+        self.tickers.iter().map(|t| t.next_due).min()
+    }
+
+    /// Returns true if any ticker with a live receiver fired.
+    fn fire_due_tickers(&mut self) -> bool {
+        let elapsed = self.elapsed;
+        let instant = self.start_instant + elapsed;
+        let mut fired = false;
+
+        self.tickers.retain_mut(|t| {
+            if t.next_due > elapsed {
+                return true;
+            }
+            // skip ticks that were missed, like `crossbeam_channel::tick` does
+            let missed = (elapsed - t.next_due).as_nanos() / t.period.as_nanos();
+            t.next_due += t.period * (missed as u32 + 1);
+            match t.sender.try_send(instant) {
+                Err(TrySendError::Disconnected(_)) => false,
+                _ => {
+                    fired = true;
+                    true
+                }
+            }
+        });
+
+        fired
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> DateTime<Utc> {
+        let state = self.state.lock().unwrap();
+        state.start_time + chrono::Duration::from_std(state.elapsed).unwrap()
+    }
+
+    fn instant(&self) -> Instant {
+        let state = self.state.lock().unwrap();
+        state.start_instant + state.elapsed
+    }
+
+    /// Like `crossbeam_channel::tick`, but `period` must not be zero (the ticker would be due forever).
+    fn tick(&self, period: Duration) -> Receiver<Instant> {
+        assert!(!period.is_zero(), "SimulatedClock cannot tick with zero period");
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        let mut state = self.state.lock().unwrap();
+        let next_due = state.elapsed + period;
+        state.tickers.push(SimulatedTicker { period, next_due, sender });
+        receiver
+    }
+
+    fn before_wait(&self) {
+        if !self.auto_advance {
+            return;
+        }
+
+        let tick_pending = {
+            let mut state = self.state.lock().unwrap();
+            let instant = state.start_instant + state.elapsed;
+            let mut tick_pending = false;
+
+            // drop tickers whose receiver is gone, eg. after the main loop replaced them;
+            // try_send() on a full channel has no effect other than reporting its state
+            state.tickers.retain(|t| {
+                if !t.sender.is_full() {
+                    return true;
+                }
+                match t.sender.try_send(instant) {
+                    Err(TrySendError::Disconnected(_)) => false,
+                    _ => {
+                        tick_pending = true;
+                        true
+                    }
+                }
+            });
+
+            tick_pending
+        };
+
+        if !tick_pending {
+            self.advance_to_next_tick();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::start_time;
+
+    #[test]
+    fn advance_moves_time_and_fires_tickers() {
+        let clock = SimulatedClock::new(start_time(), false);
+        let start_instant = clock.instant();
+        let ticks = clock.tick(Duration::from_secs(10));
+
+        clock.advance(Duration::from_secs(5));
+        assert!(ticks.try_recv().is_err());
+        assert_eq!(clock.now(), start_time() + chrono::Duration::seconds(5));
+        assert_eq!(clock.instant() - start_instant, Duration::from_secs(5));
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(ticks.try_recv().unwrap() - start_instant, Duration::from_secs(10));
+        assert!(ticks.try_recv().is_err());
+    }
+
+    #[test]
+    fn undelivered_ticks_are_dropped() {
+        let clock = SimulatedClock::new(start_time(), false);
+        let ticks = clock.tick(Duration::from_secs(10));
+
+        clock.advance(Duration::from_secs(35));
+        assert!(ticks.try_recv().is_ok());
+        assert!(ticks.try_recv().is_err());
+
+        // the ticker keeps its phase
+        clock.advance(Duration::from_secs(4));
+        assert!(ticks.try_recv().is_err());
+        clock.advance(Duration::from_secs(1));
+        assert!(ticks.try_recv().is_ok());
+        assert_eq!(clock.elapsed(), Duration::from_secs(40));
+    }
+
+    #[test]
+    fn auto_advance_jumps_to_next_tick() {
+        let clock = SimulatedClock::new(start_time(), true);
+        let slow = clock.tick(Duration::from_secs(30));
+        let fast = clock.tick(Duration::from_secs(20));
+
+        let mut fired = Vec::new();
+        for _ in 0..5 {
+            clock.before_wait();
+            crossbeam_channel::select! {
+                recv(slow) -> _ => fired.push(("slow", clock.elapsed().as_secs())),
+                recv(fast) -> _ => fired.push(("fast", clock.elapsed().as_secs())),
+            }
+        }
+
+        // both are due at 60 s, in either order
+        fired[3..].sort();
+        assert_eq!(fired, vec![("fast", 20), ("slow", 30), ("fast", 40), ("fast", 60), ("slow", 60)]);
+    }
+
+    #[test]
+    fn auto_advance_waits_for_pending_tick() {
+        let clock = SimulatedClock::new(start_time(), true);
+        let ticks = clock.tick(Duration::from_secs(10));
+
+        clock.before_wait();
+        assert_eq!(clock.elapsed(), Duration::from_secs(10));
+        // tick was not received yet, so time must not move
+        clock.before_wait();
+        assert_eq!(clock.elapsed(), Duration::from_secs(10));
+        assert!(ticks.try_recv().is_ok());
+    }
+
+    #[test]
+    fn dropped_tickers_are_skipped() {
+        let clock = SimulatedClock::new(start_time(), true);
+        drop(clock.tick(Duration::from_secs(5)));
+        let ticks = clock.tick(Duration::from_secs(60));
+
+        clock.before_wait();
+        assert!(ticks.try_recv().is_ok());
+        assert_eq!(clock.elapsed(), Duration::from_secs(60));
+    }
+
+    #[test]
+    #[should_panic(expected = "zero period")]
+    fn zero_period_is_rejected() {
+        let clock = SimulatedClock::new(start_time(), false);
+        let _ticks = clock.tick(Duration::ZERO);
+    }
+
+    const SAMPLE_EVERY: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn sample_timer_measures_time_since_previous_sample() {
+        let clock = SimulatedClock::new(start_time(), false);
+        let mut timer = SampleTimer::new(&clock);
+
+        clock.advance(Duration::from_secs(45));
+        let interval = timer.next(&clock, 2 * SAMPLE_EVERY);
+        assert_eq!(interval.duration, Duration::from_secs(45));
+        assert!(interval.suspended_since.is_none());
+
+        clock.advance(Duration::from_secs(61));
+        assert_eq!(timer.next(&clock, 2 * SAMPLE_EVERY).duration, Duration::from_secs(61));
+    }
+
+    #[test]
+    fn sample_timer_caps_long_gaps() {
+        let clock = SimulatedClock::new(start_time(), false);
+        let mut timer = SampleTimer::new(&clock);
+
+        // eg. sampling slowed down while the screen was locked
+        clock.advance(10 * SAMPLE_EVERY);
+        let interval = timer.next(&clock, 2 * SAMPLE_EVERY);
+        assert_eq!(interval.duration, 2 * SAMPLE_EVERY);
+        assert!(interval.suspended_since.is_none());
+
+        // the cap does not carry over
+        clock.advance(SAMPLE_EVERY);
+        assert_eq!(timer.next(&clock, 2 * SAMPLE_EVERY).duration, SAMPLE_EVERY);
+    }
+
+    #[test]
+    fn sample_timer_detects_suspend() {
+        let clock = SimulatedClock::new(start_time(), false);
+        let mut timer = SampleTimer::new(&clock);
+        clock.advance(SAMPLE_EVERY);
+        let last_sample = clock.now();
+        timer.next(&clock, 2 * SAMPLE_EVERY);
+
+        // suspended for an hour: the wall clock runs ahead of the monotonic clock
+        clock.shift_wall_clock(chrono::Duration::hours(1));
+        clock.advance(SAMPLE_EVERY);
+        let interval = timer.next(&clock, 2 * SAMPLE_EVERY);
+        assert_eq!(interval.suspended_since, Some(last_sample));
+        assert_eq!(interval.duration, SAMPLE_EVERY);
+
+        clock.advance(SAMPLE_EVERY);
+        assert!(timer.next(&clock, 2 * SAMPLE_EVERY).suspended_since.is_none());
+    }
+
+    #[test]
+    fn sample_timer_ignores_small_clock_adjustments() {
+        let clock = SimulatedClock::new(start_time(), false);
+        let mut timer = SampleTimer::new(&clock);
+
+        clock.shift_wall_clock(chrono::Duration::seconds(5));
+        clock.advance(SAMPLE_EVERY);
+        let interval = timer.next(&clock, 2 * SAMPLE_EVERY);
+        assert!(interval.suspended_since.is_none());
+        assert_eq!(interval.duration, SAMPLE_EVERY);
+    }
+
+    #[test]
+    fn sample_timer_ignores_clock_set_back() {
+        let clock = SimulatedClock::new(start_time(), false);
+        let mut timer = SampleTimer::new(&clock);
+
+        clock.shift_wall_clock(chrono::Duration::hours(-1));
+        clock.advance(SAMPLE_EVERY);
+        let interval = timer.next(&clock, 2 * SAMPLE_EVERY);
+        assert!(interval.suspended_since.is_none());
+        assert_eq!(interval.duration, SAMPLE_EVERY);
+
+        // later samples are measured from the adjusted time
+        clock.advance(SAMPLE_EVERY);
+        let interval = timer.next(&clock, 2 * SAMPLE_EVERY);
+        assert!(interval.suspended_since.is_none());
+        assert_eq!(interval.duration, SAMPLE_EVERY);
+    }
+}
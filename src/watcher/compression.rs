@@ -0,0 +1,129 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use anyhow::{bail, Result};
+use json::JsonValue;
+use crate::watcher::encryption::ENCRYPTED_EXTENSION;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Compression of output `.jsonl` files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub fn from_json(val: &JsonValue) -> Result<Compression> {
+        match val.as_str() {
+            _ if val.is_null() => Ok(Compression::None),
+            Some("none") => Ok(Compression::None),
+            Some("gzip") => Ok(Compression::Gzip),
+            Some("zstd") => Ok(Compression::Zstd),
+            _ => bail!("compression must be one of \"none\", \"gzip\", \"zstd\" or null, not {:?}", val)
+        }
+    }
+
+    /// File name extension, including the `.jsonl` part.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::None => "jsonl",
+            Compression::Gzip => "jsonl.gz",
+            Compression::Zstd => "jsonl.zst",
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+            Compression::Zstd => Ok(zstd::encode_all(data, zstd::DEFAULT_COMPRESSION_LEVEL)?),
+        }
+    }
+
+    /// Detect compression from the first bytes of a file.
+    pub fn detect(header: &[u8]) -> Compression {
+        if header.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if header.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+}
+
+/// Whether file name looks like an event log, ie. `.jsonl` with optional compression and encryption suffix.
+pub fn is_event_log_file(path: &Path) -> bool {
+    let name = path.file_name().and_then(|s| s.to_str()).unwrap_or_default();
+    let name = name.strip_suffix(&format!(".{}", ENCRYPTED_EXTENSION)).unwrap_or(name);
+    [Compression::None, Compression::Gzip, Compression::Zstd].iter()
+        .any(|c| name.ends_with(&format!(".{}", c.extension())))
+}
+
+/// Open file for reading, transparently decompressing it if needed.
+///
+/// See `encryption::open_reader` for files that may also be encrypted.
+pub fn open_reader(path: &Path) -> Result<Box<dyn BufRead>> {
+    decompress_reader(BufReader::new(fs::File::open(path)?))
+}
+
+/// Wrap reader to transparently decompress its data if needed.
+pub fn decompress_reader<R: BufRead + 'static>(mut reader: R) -> Result<Box<dyn BufRead>> {
+    let compression = Compression::detect(reader.fill_buf()?);
+
+    let reader: Box<dyn BufRead> = match compression {
+        Compression::None => Box::new(reader),
+        Compression::Gzip => Box::new(BufReader::new(flate2::bufread::MultiGzDecoder::new(reader))),
+        Compression::Zstd => Box::new(BufReader::new(zstd::Decoder::with_buffer(reader)?)),
+    };
+    Ok(reader)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    const DATA: &[u8] = b"{\"a\":1}\n{\"b\":2}\n";
+
+    #[test]
+    fn compressed_data_round_trips() {
+        for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+            let compressed = compression.compress(DATA).unwrap();
+            assert_eq!(Compression::detect(&compressed), compression);
+
+            let mut data = Vec::new();
+            decompress_reader(std::io::Cursor::new(compressed)).unwrap().read_to_end(&mut data).unwrap();
+            assert_eq!(data, DATA, "{:?}", compression);
+        }
+    }
+
+    #[test]
+    fn concatenated_gzip_members_are_read() {
+        let mut compressed = Compression::Gzip.compress(b"{\"a\":1}\n").unwrap();
+        compressed.extend(Compression::Gzip.compress(b"{\"b\":2}\n").unwrap());
+
+        let mut data = Vec::new();
+        decompress_reader(std::io::Cursor::new(compressed)).unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, DATA);
+    }
+
+    #[test]
+    fn event_log_file_names() {
+        for name in ["a.jsonl", "a.jsonl.gz", "a.jsonl.zst", "a.jsonl.age", "a.jsonl.zst.age"] {
+            assert!(is_event_log_file(Path::new(name)), "{}", name);
+        }
+        for name in ["a.json", "a.gz", "a.jsonl.tmp", "a.age"] {
+            assert!(!is_event_log_file(Path::new(name)), "{}", name);
+        }
+    }
+}
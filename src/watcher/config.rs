@@ -1,15 +1,145 @@
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
+use chrono::{Datelike, Local, NaiveTime, Timelike, Weekday};
 use regex::Regex;
-use anyhow::{anyhow, bail, Result};
+use sha1::{Sha1, Digest};
+use anyhow::{anyhow, bail, Context, Result};
 use json::JsonValue;
+use crate::watcher::anonymize::AnonymizeMode;
+use crate::watcher::compression::Compression;
 use crate::watcher::core::ActiveWindowEvent;
+use crate::watcher::encryption;
 
 #[derive(Debug)]
 pub struct WindowEventMatcher {
     pub window_title_regex: Option<Regex>,
-    pub process_path_regex: Option<Regex>
+    pub process_path_regex: Option<Regex>,
+    pub process_name_regex: Option<Regex>,
+    pub hostname_regex: Option<Regex>,
+    pub username_regex: Option<Regex>,
+    /// Days of week (in local time) when the matcher applies, empty means any day.
+    pub weekdays: Vec<Weekday>,
+    /// Times of day (in local time) when the matcher applies, empty means any time.
+    pub time_of_day: Vec<TimeOfDayRange>,
+    pub idle_for: Option<IdleRange>,
+    pub all: Vec<WindowEventMatcher>,
+    pub any: Vec<WindowEventMatcher>,
+    pub not: Option<Box<WindowEventMatcher>>,
+}
+
+/// Range of local time of day, `end` is exclusive; if `end` is before `start`, the range spans midnight,
+/// if they are equal, the range is the whole day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeOfDayRange {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl TimeOfDayRange {
+    pub fn contains(&self, t: NaiveTime) -> bool {
+        if self.start == self.end {
+            true
+        } else if self.start < self.end {
+            self.start <= t && t < self.end
+        } else {
+            t >= self.start || t < self.end
+        }
+    }
+
+    /// Parse range like `"09:00-17:30"`; `"24:00"` is accepted as the end of day.
+    pub fn parse(s: &str) -> Result<TimeOfDayRange> {
+        let (start, end) = s.split_once('-').ok_or(anyhow!("time of day range must look like \"09:00-17:00\", not {:?}", s))?;
+        let parse = |t: &str| match t.trim() {
+            "24:00" => Ok(NaiveTime::MIN),
+            t => NaiveTime::parse_from_str(t, "%H:%M").map_err(|e| anyhow!("invalid time {:?} (expected HH:MM): {}", t, e))
+        };
+        Ok(TimeOfDayRange { start: parse(start)?, end: parse(end)? })
+    }
+}
+
+/// Range of idle durations, `min` is inclusive and `max` is exclusive, missing bound means unbounded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdleRange {
+    pub min: Option<Duration>,
+    pub max: Option<Duration>,
+}
+
+impl IdleRange {
+    pub fn contains(&self, d: Duration) -> bool {
+        self.min.is_none_or(|min| d >= min) && self.max.is_none_or(|max| d < max)
+    }
+}
+
+/// Regex substitution applied to window title of events that match `matcher` (or all events if there is none).
+#[derive(Debug)]
+pub struct RewriteRule {
+    pub matcher: Option<WindowEventMatcher>,
+    pub pattern: Regex,
+    pub replacement: String,
+}
+
+impl RewriteRule {
+    pub fn apply(&self, e: &mut ActiveWindowEvent) {
+        if self.matcher.as_ref().is_none_or(|m| m.matches(e)) {
+            e.window_title = self.pattern.replace_all(e.window_title.as_str(), self.replacement.as_str()).into_owned();
+        }
+    }
+
+    pub fn from_json_single(val: &JsonValue, path: &str) -> Result<RewriteRule> {
+        if !val.is_object() {
+            bail!("{}: rewrite rule should be an object, not {}", path, json_type_name(val));
+        }
+        check_keys(val, path, &REWRITE_KEYS)?;
+
+        let matcher = &val["match"];
+        let matcher = if matcher.is_null() {
+            None
+        } else {
+            Some(WindowEventMatcher::from_json_single(matcher, &child_path(path, "match"))?)
+        };
+        let pattern = read_regex(&val["pattern"], &child_path(path, "pattern"))?
+            .ok_or(anyhow!("{}: missing", child_path(path, "pattern")))?;
+        let replacement = read_str(&val["replacement"], &child_path(path, "replacement"))?
+            .ok_or(anyhow!("{}: missing", child_path(path, "replacement")))?
+            .to_string();
+
+        Ok(RewriteRule { matcher, pattern, replacement })
+    }
+
+    pub fn from_json(val: &JsonValue, path: &str) -> Result<Vec<RewriteRule>> {
+        parse_one_or_many(val, path, RewriteRule::from_json_single)
+    }
+}
+
+#[derive(Debug)]
+pub struct AnonymizeRule {
+    pub matcher: WindowEventMatcher,
+    pub mode: AnonymizeMode,
+}
+
+impl AnonymizeRule {
+    /// Parse single rule, either a `WindowEventMatcher` object (redacting events that match it)
+    /// or an object with `"match"` and `"mode"` keys.
+    pub fn from_json_single(val: &JsonValue, path: &str) -> Result<AnonymizeRule> {
+        if !val.is_object() || val["match"].is_null() {
+            let matcher = WindowEventMatcher::from_json_single(val, path)?;
+            return Ok(AnonymizeRule { matcher, mode: AnonymizeMode::Redact });
+        }
+        check_keys(val, path, &ANONYMIZE_KEYS)?;
+
+        let matcher = WindowEventMatcher::from_json_single(&val["match"], &child_path(path, "match"))?;
+        let mode = match read_str(&val["mode"], &child_path(path, "mode"))? {
+            Some(tmp) => AnonymizeMode::parse(tmp).map_err(|e| anyhow!("{}: {}", child_path(path, "mode"), e))?,
+            None => AnonymizeMode::Redact,
+        };
+
+        Ok(AnonymizeRule { matcher, mode })
+    }
+
+    pub fn from_json(val: &JsonValue, path: &str) -> Result<Vec<AnonymizeRule>> {
+        parse_one_or_many(val, path, AnonymizeRule::from_json_single)
+    }
 }
 
 #[derive(Debug)]
@@ -34,51 +164,127 @@ impl WindowEventMatcher {
             }
         }
 
+        if let Some(tmp) = &self.process_name_regex {
+            match e.process_path.file_name().and_then(|s| s.to_str()) {
+                Some(name) if tmp.is_match(name) => {}
+                _ => return false,
+            }
+        }
+
+        if let Some(tmp) = &self.hostname_regex {
+            if !tmp.is_match(e.hostname.as_str()) {
+                return false;
+            }
+        }
+
+        if let Some(tmp) = &self.username_regex {
+            if !tmp.is_match(e.username.as_str()) {
+                return false;
+            }
+        }
+
+        if !self.weekdays.is_empty() || !self.time_of_day.is_empty() {
+            let local_time = e.time.with_timezone(&Local);
+
+            if !self.weekdays.is_empty() && !self.weekdays.contains(&local_time.weekday()) {
+                return false;
+            }
+
+            let t = NaiveTime::from_hms_opt(local_time.hour(), local_time.minute(), local_time.second()).unwrap();
+            if !self.time_of_day.is_empty() && !self.time_of_day.iter().any(|r| r.contains(t)) {
+                return false;
+            }
+        }
+
+        if let Some(tmp) = &self.idle_for {
+            if !tmp.contains(e.idle_for) {
+                return false;
+            }
+        }
+
+        if !self.all.iter().all(|m| m.matches(e)) {
+            return false;
+        }
+
+        if !self.any.is_empty() && !self.any.iter().any(|m| m.matches(e)) {
+            return false;
+        }
+
+        if let Some(tmp) = &self.not {
+            if tmp.matches(e) {
+                return false;
+            }
+        }
+
         true
     }
 
-    pub fn from_json_single(val: &JsonValue) -> Result<WindowEventMatcher> {
+    fn from_json_list(val: &JsonValue, path: &str) -> Result<Vec<WindowEventMatcher>> {
+        if val.is_null() {
+            return Ok(vec![]);
+        }
+        if !val.is_array() || val.is_empty() {
+            bail!("{}: must be a non-empty array of WindowEventMatcher objects, not {}", path, json_type_name(val));
+        }
+
+        let mut matchers = Vec::<WindowEventMatcher>::new();
+        for (i, v) in val.members().enumerate() {
+            matchers.push(WindowEventMatcher::from_json_single(v, &index_path(path, i))?);
+        }
+        Ok(matchers)
+    }
+
+    pub fn from_json_single(val: &JsonValue, path: &str) -> Result<WindowEventMatcher> {
         if !val.is_object() {
-            bail!("WindowEventMatcher definition should be an object, not {:?}", val);
+            bail!("{}: WindowEventMatcher definition should be an object, not {}", path, json_type_name(val));
         }
+        check_keys(val, path, &MATCHER_KEYS)?;
 
-        let window_title = &val["window_title"];
-        let process_path = &val["process_path"];
+        let window_title_regex = read_regex(&val["window_title"], &child_path(path, "window_title"))?;
+        let process_path_regex = read_regex(&val["process_path"], &child_path(path, "process_path"))?;
+        let process_name_regex = read_regex(&val["process_name"], &child_path(path, "process_name"))?;
+        let hostname_regex = read_regex(&val["hostname"], &child_path(path, "hostname"))?;
+        let username_regex = read_regex(&val["username"], &child_path(path, "username"))?;
+        let weekdays = read_weekdays(&val["weekday"], &child_path(path, "weekday"))?;
+        let time_of_day = read_time_of_day(&val["time_of_day"], &child_path(path, "time_of_day"))?;
+        let idle_for = read_idle_range(&val["idle_for"], &child_path(path, "idle_for"))?;
 
-        let window_title_regex = if let Some(tmp) = window_title.as_str() {
-            Some(Regex::new(tmp)?)
-        } else { None };
+        let all = WindowEventMatcher::from_json_list(&val["all"], &child_path(path, "all"))?;
+        let any = WindowEventMatcher::from_json_list(&val["any"], &child_path(path, "any"))?;
 
-        let process_path_regex = if let Some(tmp) = process_path.as_str() {
-            Some(Regex::new(tmp)?)
-        } else { None };
+        let not = &val["not"];
+        let not = if not.is_null() {
+            None
+        } else if not.is_object() {
+            Some(Box::new(WindowEventMatcher::from_json_single(not, &child_path(path, "not"))?))
+        } else {
+            bail!("{}: must be a WindowEventMatcher object, not {}", child_path(path, "not"), json_type_name(not));
+        };
 
-        if ![&window_title_regex, &process_path_regex].iter().any(|tmp| tmp.is_some()) {
-            bail!("WindowEventMatcher must define at least one of window_title, process_path");
+        let has_regex = [&window_title_regex, &process_path_regex, &process_name_regex, &hostname_regex, &username_regex]
+            .iter().any(|tmp| tmp.is_some());
+        if !has_regex && weekdays.is_empty() && time_of_day.is_empty() && idle_for.is_none()
+            && all.is_empty() && any.is_empty() && not.is_none() {
+            bail!("{}: WindowEventMatcher must define at least one of {}", path, MATCHER_KEYS.join(", "));
         }
 
         Ok(WindowEventMatcher {
-            window_title_regex: window_title_regex,
-            process_path_regex: process_path_regex,
+            window_title_regex,
+            process_path_regex,
+            process_name_regex,
+            hostname_regex,
+            username_regex,
+            weekdays,
+            time_of_day,
+            idle_for,
+            all,
+            any,
+            not,
         })
     }
 
-    pub fn from_json(val: &JsonValue) -> Result<Vec<WindowEventMatcher>> {
-        if val.is_array() {
-            let mut matchers = Vec::<WindowEventMatcher>::new();
-
-            for v in val.members() {
-                matchers.push(WindowEventMatcher::from_json_single(v)?);
-            }
-
-            Ok(matchers)
-        } else if val.is_object() {
-            Ok(vec![WindowEventMatcher::from_json_single(val)?])
-        } else if val.is_null() {
-            Ok(vec![])
-        } else {
-            bail!("WindowEventMatcher(s) JSON definition must be object, array, or null")
-        }
+    pub fn from_json(val: &JsonValue, path: &str) -> Result<Vec<WindowEventMatcher>> {
+        parse_one_or_many(val, path, WindowEventMatcher::from_json_single)
     }
 }
 
@@ -87,71 +293,205 @@ pub struct Config {
     pub output_dir: PathBuf,
     pub sample_every: Duration,
     pub write_every: Duration,
+    /// Events idle for at least this long get `EventStatus::Afk`, `None` disables this.
+    pub afk_after: Option<Duration>,
+    /// Whether to merge consecutive samples of the same window into spans when writing.
+    pub coalesce: bool,
+    pub compression: Compression,
+    pub encrypt_to: Vec<age::x25519::Recipient>,
+    pub scripted_desktop: Option<PathBuf>,
+    pub state_dir: PathBuf,
+    /// Secret key for anonymize rules with `AnonymizeMode::Hash`, created on first use.
+    pub anonymize_key_path: PathBuf,
+    /// SHA-1 of this file and all base configs that were read, to tell which configuration the daemon ran with.
+    pub hash: String,
+    /// Whether to reload configuration when this file or any of its base configs changes.
+    pub reload_on_change: bool,
+    /// Base configs included by this config, directly or through other base configs, in merge order
+    /// (whether they could be read or not).
+    pub base_config_paths: Vec<PathBuf>,
     pub tags: Vec<ConfigTag>,
     pub ignore: Vec<WindowEventMatcher>,
-    pub anonymize: Vec<WindowEventMatcher>,
+    pub anonymize: Vec<AnonymizeRule>,
+    pub rewrite: Vec<RewriteRule>,
 }
 
 #[derive(Debug)]
 pub struct BaseConfig {
     pub tags: Vec<ConfigTag>,
     pub ignore: Vec<WindowEventMatcher>,
-    pub anonymize: Vec<WindowEventMatcher>,
+    pub anonymize: Vec<AnonymizeRule>,
+    pub rewrite: Vec<RewriteRule>,
+    /// Base configs included by this base config.
+    pub base_configs: Vec<BaseConfigRef>,
+    /// All base configs that were merged into this one (only used when merging).
+    base_config_paths: Vec<PathBuf>,
+}
+
+/// Entry of `"path_to_base_config"`.
+#[derive(Debug, Clone)]
+pub struct BaseConfigRef {
+    pub path: PathBuf,
+    /// If a required base config cannot be read (and there is no cached copy), the whole configuration fails to load;
+    /// optional base configs are skipped with a warning.
+    pub required: bool,
+}
+
+/// Last-known-good copies of base configs, used when the original cannot be read (eg. network drive is offline).
+struct BaseConfigCache {
+    dir: PathBuf,
+    /// Whether to store copies of base configs that were read successfully.
+    update: bool,
+}
+
+impl BaseConfigCache {
+    /// Copies are keyed by absolute path of the original, which (unlike canonical path) does not depend on it being reachable.
+    fn cache_path(&self, path: &Path) -> PathBuf {
+        let absolute_path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+        let mut hasher = Sha1::new();
+        hasher.update(absolute_path.to_string_lossy().as_bytes());
+        self.dir.join(format!("{:02x}.json", hasher.finalize()))
+    }
+
+    fn load(&self, path: &Path) -> Option<(PathBuf, String)> {
+        let cache_path = self.cache_path(path);
+        let data = fs::read_to_string(&cache_path).ok()?;
+        Some((cache_path, data))
+    }
+
+    fn store(&self, path: &Path, data: &str) -> Result<()> {
+        if !self.update {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.dir)?;
+        let cache_path = self.cache_path(path);
+        let tmp_path = cache_path.with_extension("json.tmp");
+        fs::write(&tmp_path, data)?;
+        fs::rename(&tmp_path, &cache_path)?;
+        Ok(())
+    }
 }
 
 impl Config {
+    /// Anonymization to apply to the event, `AnonymizeMode::Redact` wins over `AnonymizeMode::Hash` if both match.
+    pub fn anonymize_mode(&self, e: &ActiveWindowEvent) -> Option<AnonymizeMode> {
+        self.anonymize.iter()
+            .filter(|r| r.matcher.matches(e))
+            .map(|r| r.mode)
+            .max()
+    }
+
+    /// Whether any anonymize rule needs the key at `anonymize_key_path`.
+    pub fn uses_anonymize_key(&self) -> bool {
+        self.anonymize.iter().any(|r| r.mode == AnonymizeMode::Hash)
+    }
+
+    /// Whether window titles and process paths should be kept out of the daemon's own output,
+    /// as event logs are encrypted or anonymized.
+    pub fn redact_log(&self) -> bool {
+        !self.encrypt_to.is_empty() || !self.anonymize.is_empty()
+    }
+
+    /// Read configuration file and its base configs, updating cached copies of base configs in `state_dir`.
     pub fn from_file(path: &Path) -> Result<Config> {
+        Config::read_file(path, true)
+    }
+
+    /// Read configuration like `from_file`, without updating the base config cache (eg. for `check-config`).
+    pub fn validate_file(path: &Path) -> Result<Config> {
+        Config::read_file(path, false)
+    }
+
+    fn read_file(path: &Path, update_cache: bool) -> Result<Config> {
         let data = fs::read_to_string(path)?;
         let d = json::parse(data.as_str())?;
+        let mut hasher = Sha1::new();
+        hasher.update(data.as_bytes());
+        Config::from_json(&d, path, update_cache, hasher).with_context(|| format!("invalid configuration file {:?}", path))
+    }
 
-        let mut tags = Config::read_tags(&d["tags"])?;
-        let mut ignore = WindowEventMatcher::from_json(&d["ignore"])?;
-        let mut anonymize = WindowEventMatcher::from_json(&d["anonymize"])?;
-
-        // import tags, ignore, anonymize from base config
-        if let Some(path_to_base_config_str) = d["main"]["path_to_base_config"].as_str() {
-            let relative_path_to_base_config = PathBuf::from(path_to_base_config_str);
-            let path_to_base_config = path.parent().unwrap().join(relative_path_to_base_config);
-            match BaseConfig::from_file(path_to_base_config.as_path()) {
-                Ok(base_config) => {
-                    tags.extend(base_config.tags);
-                    ignore.extend(base_config.ignore);
-                    anonymize.extend(base_config.anonymize);
-                }
-                Err(e) => {
-                    eprintln!("failed to read base_config {:?}: {:?}", path_to_base_config, e);
-                }
-            }
+    fn from_json(d: &JsonValue, path: &Path, update_cache: bool, mut hasher: Sha1) -> Result<Config> {
+        if !d.is_object() {
+            bail!("configuration must be an object, not {}", json_type_name(d));
         }
+        check_keys(d, "", &TOP_LEVEL_KEYS)?;
+        let main = &d["main"];
+        if !main.is_object() {
+            bail!("main: must be an object, not {}", json_type_name(main));
+        }
+        check_keys(main, "main", &MAIN_KEYS)?;
+
+        let mut tags = Config::read_tags(&d["tags"], "tags")?;
+        let mut ignore = WindowEventMatcher::from_json(&d["ignore"], "ignore")?;
+        let mut anonymize = AnonymizeRule::from_json(&d["anonymize"], "anonymize")?;
+        let mut rewrite = RewriteRule::from_json(&d["rewrite"], "rewrite")?;
 
-        let relative_output_dir = PathBuf::from(d["main"]["output_dir"].as_str().ok_or(anyhow!("cannot read output_dir"))?);
+        let relative_output_dir = PathBuf::from(read_str(&main["output_dir"], "main.output_dir")?.ok_or(anyhow!("main.output_dir: missing"))?);
         let output_dir = path.parent().unwrap().join(relative_output_dir);
-        let sample_every = Duration::from_secs_f32(d["main"]["sample_every_sec"].as_f32().ok_or(anyhow!("cannot read sample_every_sec"))?);
-        let write_every = Duration::from_secs_f32(d["main"]["write_every_sec"].as_f32().ok_or(anyhow!("cannot read write_every_sec"))?);
+        let sample_every = read_positive_duration_sec(&main["sample_every_sec"], "main.sample_every_sec")?;
+        let write_every = read_positive_duration_sec(&main["write_every_sec"], "main.write_every_sec")?;
+        let afk_after = read_duration_sec(&main["afk_after_sec"], "main.afk_after_sec")?;
+        let coalesce = read_bool(&main["coalesce"], "main.coalesce")?.unwrap_or(false);
+        let reload_on_change = read_bool(&main["reload_on_change"], "main.reload_on_change")?.unwrap_or(true);
+        let compression = Compression::from_json(&main["compression"]).map_err(|e| anyhow!("main.compression: {}", e))?;
+        let encrypt_to = encryption::recipients_from_json(&main["encrypt_to"]).map_err(|e| anyhow!("main.encrypt_to: {}", e))?;
+        let scripted_desktop = read_str(&main["scripted_desktop"], "main.scripted_desktop")?
+            .map(|tmp| path.parent().unwrap().join(tmp));
+        let relative_state_dir = PathBuf::from(read_str(&main["state_dir"], "main.state_dir")?.unwrap_or("state"));
+        let state_dir = path.parent().unwrap().join(relative_state_dir);
+        let anonymize_key_path = match read_str(&main["anonymize_key_file"], "main.anonymize_key_file")? {
+            Some(tmp) => path.parent().unwrap().join(tmp),
+            None => state_dir.join("anonymize.key"),
+        };
+
+        // import tags, ignore, anonymize, rewrite from base configs
+        let mut base = BaseConfig::empty();
+        let mut chain = vec![canonical_path(path)];
+        let mut visited = Vec::new();
+        let cache = BaseConfigCache { dir: state_dir.join("base_config_cache"), update: update_cache };
+        BaseConfig::read_chained(&read_base_config_refs(main, path)?, &mut chain, &mut visited, &cache, &mut base, &mut hasher)?;
+        tags.extend(base.tags);
+        ignore.extend(base.ignore);
+        anonymize.extend(base.anonymize);
+        rewrite.extend(base.rewrite);
+        let base_config_paths = base.base_config_paths;
+        let hash = format!("{:02x}", hasher.finalize());
 
         Ok(Config {
             output_dir,
             sample_every,
             write_every,
+            afk_after,
+            coalesce,
+            compression,
+            encrypt_to,
+            scripted_desktop,
+            state_dir,
+            anonymize_key_path,
+            reload_on_change,
+            base_config_paths,
+            hash,
             tags,
             ignore,
             anonymize,
+            rewrite,
         })
     }
 
-    pub fn read_tags(obj: &JsonValue) -> Result<Vec<ConfigTag>> {
+    /// Parse the `"tags"` object.
+    pub fn read_tags(obj: &JsonValue, path: &str) -> Result<Vec<ConfigTag>> {
         if obj.is_null() {
             return Ok(vec![]);
         }
 
         if !obj.is_object() {
-            bail!("JSON value of 'tags' key must be JSON object or null");
+            bail!("{}: must be JSON object or null, not {}", path, json_type_name(obj));
         }
 
         let mut tags = Vec::<ConfigTag>::new();
 
         for (key, val) in obj.entries() {
-            let matchers = WindowEventMatcher::from_json(val)?;
+            let matchers = WindowEventMatcher::from_json(val, &child_path(path, key))?;
 
             for matcher in matchers {
                 tags.push( ConfigTag {
@@ -166,14 +506,612 @@ impl Config {
 }
 
 impl BaseConfig {
+    /// Read a single base config; base configs it includes are not read, only listed in `base_configs`.
     pub fn from_file(path: &Path) -> Result<BaseConfig> {
         let data = fs::read_to_string(path)?;
-        let d = json::parse(data.as_str())?;
+        BaseConfig::parse(data.as_str(), path)
+    }
+
+    /// Parse base config that was read from `path` (used to resolve relative paths and in error messages).
+    pub fn parse(data: &str, path: &Path) -> Result<BaseConfig> {
+        let d = json::parse(data).with_context(|| format!("invalid base configuration file {:?}", path))?;
+        BaseConfig::from_json(&d, path).with_context(|| format!("invalid base configuration file {:?}", path))
+    }
+
+    fn from_json(d: &JsonValue, path: &Path) -> Result<BaseConfig> {
+        if !d.is_object() {
+            bail!("configuration must be an object, not {}", json_type_name(d));
+        }
+        // base config may be a complete config of another machine
+        check_keys(d, "", &TOP_LEVEL_KEYS)?;
+        let main = &d["main"];
+        let base_configs = if main.is_null() {
+            vec![]
+        } else if main.is_object() {
+            check_keys(main, "main", &MAIN_KEYS)?;
+            read_base_config_refs(main, path)?
+        } else {
+            bail!("main: must be an object or null, not {}", json_type_name(main));
+        };
+
+        let tags = Config::read_tags(&d["tags"], "tags")?;
+        let ignore = WindowEventMatcher::from_json(&d["ignore"], "ignore")?;
+        let anonymize = AnonymizeRule::from_json(&d["anonymize"], "anonymize")?;
+        let rewrite = RewriteRule::from_json(&d["rewrite"], "rewrite")?;
+
+        Ok(BaseConfig { tags, ignore, anonymize, rewrite, base_configs, base_config_paths: vec![] })
+    }
+
+    fn empty() -> BaseConfig {
+        BaseConfig { tags: vec![], ignore: vec![], anonymize: vec![], rewrite: vec![], base_configs: vec![], base_config_paths: vec![] }
+    }
+
+    /// Read base configs and the base configs they include, depth-first in the order they are listed,
+    /// appending their rules to `merged`, their paths to `merged.base_config_paths` and their content to `hasher`.
+    ///
+    /// `chain` holds canonical paths of configs currently being read (to detect cycles), `visited`
+    /// those already read (a base config included from several places is only read once).
+    fn read_chained(refs: &[BaseConfigRef], chain: &mut Vec<PathBuf>, visited: &mut Vec<PathBuf>,
+                    cache: &BaseConfigCache, merged: &mut BaseConfig, hasher: &mut Sha1) -> Result<()> {
+        for r in refs {
+            let path = &r.path;
+            let key = canonical_path(path);
+            if chain.contains(&key) {
+                let cycle: Vec<String> = chain.iter().chain([&key]).map(|p| format!("{:?}", p)).collect();
+                bail!("base configs include each other: {}", cycle.join(" -> "));
+            }
+            if visited.contains(&key) {
+                continue;
+            }
+            visited.push(key.clone());
+            merged.base_config_paths.push(path.clone());
+
+            let read = fs::read_to_string(path)
+                .map_err(|e| anyhow!("cannot read {:?}: {}", path, e))
+                .and_then(|data| Ok((BaseConfig::parse(data.as_str(), path)?, data)));
+            let (base_config, data) = match read {
+                Ok((base_config, data)) => {
+                    if let Err(e) = cache.store(path, data.as_str()) {
+                        eprintln!("failed to cache base_config {:?}: {:?}", path, e);
+                    }
+                    (base_config, data)
+                }
+                // eg. network drive is offline, or the file is only partially synced
+                Err(e) => match cache.load(path).map(|(cache_path, data)| (cache_path, BaseConfig::parse(data.as_str(), path), data)) {
+                    Some((cache_path, Ok(base_config), data)) => {
+                        eprintln!("failed to read base_config {:?} ({:#}), using cached copy {:?}", path, e, cache_path);
+                        (base_config, data)
+                    }
+                    _ if r.required => bail!("failed to read required base_config {:?}: {:#}", path, e),
+                    _ => {
+                        eprintln!("failed to read optional base_config {:?}, skipping it: {:#}", path, e);
+                        continue;
+                    }
+                }
+            };
+
+            hasher.update(data.as_bytes());
+            merged.tags.extend(base_config.tags);
+            merged.ignore.extend(base_config.ignore);
+            merged.anonymize.extend(base_config.anonymize);
+            merged.rewrite.extend(base_config.rewrite);
+
+            chain.push(key);
+            BaseConfig::read_chained(&base_config.base_configs, chain, visited, cache, merged, hasher)?;
+            chain.pop();
+        }
+        Ok(())
+    }
+}
+
+/// Read `"path_to_base_config"` of `main` object of config at `path`, relative paths are taken from its directory.
+fn read_base_config_refs(main: &JsonValue, path: &Path) -> Result<Vec<BaseConfigRef>> {
+    let val = &main["path_to_base_config"];
+    let items: Vec<(&JsonValue, String)> = if val.is_array() {
+        val.members().enumerate().map(|(i, v)| (v, index_path("main.path_to_base_config", i))).collect()
+    } else if val.is_null() {
+        vec![]
+    } else {
+        vec![(val, "main.path_to_base_config".to_string())]
+    };
+
+    let mut refs = Vec::new();
+    for (v, item_path) in items {
+        let r = if let Some(tmp) = v.as_str() {
+            BaseConfigRef { path: path.parent().unwrap().join(tmp), required: true }
+        } else if v.is_object() {
+            check_keys(v, &item_path, &BASE_CONFIG_REF_KEYS)?;
+            let tmp = read_str(&v["path"], &child_path(&item_path, "path"))?
+                .ok_or(anyhow!("{}: missing", child_path(&item_path, "path")))?;
+            let required = read_bool(&v["required"], &child_path(&item_path, "required"))?.unwrap_or(true);
+            BaseConfigRef { path: path.parent().unwrap().join(tmp), required }
+        } else {
+            bail!("{}: must be a string or object with \"path\" and \"required\", not {}", item_path, json_type_name(v));
+        };
+        refs.push(r);
+    }
+    Ok(refs)
+}
+
+/// Path used to tell whether two paths refer to the same config file.
+fn canonical_path(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+const TOP_LEVEL_KEYS: [&str; 5] = ["main", "ignore", "anonymize", "tags", "rewrite"];
+const MAIN_KEYS: [&str; 12] = ["output_dir", "sample_every_sec", "write_every_sec", "path_to_base_config",
+                               "compression", "encrypt_to", "state_dir", "scripted_desktop", "afk_after_sec",
+                               "anonymize_key_file", "reload_on_change", "coalesce"];
+const MATCHER_KEYS: [&str; 11] = ["window_title", "process_path", "process_name", "hostname", "username",
+                                   "weekday", "time_of_day", "idle_for", "all", "any", "not"];
+const BASE_CONFIG_REF_KEYS: [&str; 2] = ["path", "required"];
+const ANONYMIZE_KEYS: [&str; 2] = ["match", "mode"];
+const REWRITE_KEYS: [&str; 3] = ["match", "pattern", "replacement"];
+const IDLE_RANGE_KEYS: [&str; 2] = ["min_sec", "max_sec"];
+
+fn child_path(path: &str, key: &str) -> String {
+    let is_identifier = !key.is_empty() && key.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-');
+    match (path.is_empty(), is_identifier) {
+        (true, true) => key.to_string(),
+        (false, true) => format!("{}.{}", path, key),
+        (_, false) => format!("{}[{:?}]", path, key),
+    }
+}
+
+fn index_path(path: &str, i: usize) -> String {
+    format!("{}[{}]", path, i)
+}
+
+/// Parse `val` as one object or an array of objects, with `parse`; null gives none.
+///
+/// Like the other parsers of this module, it takes the `path` of `val` in the config file
+/// (eg. `tags.work.all[0]`, see `child_path` and `index_path`) to prefix error messages with.
+fn parse_one_or_many<T>(val: &JsonValue, path: &str, parse: impl Fn(&JsonValue, &str) -> Result<T>) -> Result<Vec<T>> {
+    match val {
+        JsonValue::Null => Ok(vec![]),
+        JsonValue::Object(_) => Ok(vec![parse(val, path)?]),
+        JsonValue::Array(items) => items.iter().enumerate()
+            .map(|(i, v)| parse(v, &index_path(path, i)))
+            .collect(),
+        _ => bail!("{}: must be an object, an array of objects, or null, not {}", path, json_type_name(val)),
+    }
+}
+
+fn json_type_name(val: &JsonValue) -> &'static str {
+    match val {
+        JsonValue::Null => "null",
+        JsonValue::Short(_) | JsonValue::String(_) => "string",
+        JsonValue::Number(_) => "number",
+        JsonValue::Boolean(_) => "boolean",
+        JsonValue::Object(_) => "object",
+        JsonValue::Array(_) => "array",
+    }
+}
+
+/// Fail on keys that are not in `known_keys`, suggesting the closest known key.
+pub(crate) fn check_keys(val: &JsonValue, path: &str, known_keys: &[&str]) -> Result<()> {
+    for (key, _) in val.entries() {
+        if known_keys.contains(&key) {
+            continue;
+        }
+
+        let suggestion = known_keys.iter()
+            .map(|k| (edit_distance(key, k), k))
+            .filter(|(d, k)| *d <= 2.max(k.len() / 3))
+            .min();
+        match suggestion {
+            Some((_, k)) => bail!("{}: unknown key, did you mean {:?}?", child_path(path, key), k),
+            None => bail!("{}: unknown key, expected one of {}", child_path(path, key), known_keys.join(", ")),
+        }
+    }
+    Ok(())
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            cur[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        prev = cur;
+    }
+
+    prev[b.len()]
+}
+
+fn read_str<'a>(val: &'a JsonValue, path: &str) -> Result<Option<&'a str>> {
+    if val.is_null() {
+        Ok(None)
+    } else if let Some(tmp) = val.as_str() {
+        Ok(Some(tmp))
+    } else {
+        bail!("{}: must be a string, not {}", path, json_type_name(val))
+    }
+}
+
+fn read_bool(val: &JsonValue, path: &str) -> Result<Option<bool>> {
+    match val {
+        JsonValue::Null => Ok(None),
+        JsonValue::Boolean(tmp) => Ok(Some(*tmp)),
+        _ => bail!("{}: must be a boolean, not {}", path, json_type_name(val)),
+    }
+}
+
+/// Read required positive number of seconds.
+fn read_positive_duration_sec(val: &JsonValue, path: &str) -> Result<Duration> {
+    match val.as_f32() {
+        Some(tmp) if tmp > 0.0 => seconds_to_duration(tmp, path),
+        Some(_) => bail!("{}: must be a positive number", path),
+        None if val.is_null() => bail!("{}: missing", path),
+        None => bail!("{}: must be a number, not {}", path, json_type_name(val)),
+    }
+}
+
+/// Read optional non-negative number of seconds.
+fn read_duration_sec(val: &JsonValue, path: &str) -> Result<Option<Duration>> {
+    match val.as_f32() {
+        Some(tmp) if tmp >= 0.0 => Ok(Some(seconds_to_duration(tmp, path)?)),
+        Some(_) => bail!("{}: must not be negative", path),
+        None if val.is_null() => Ok(None),
+        None => bail!("{}: must be a number, not {}", path, json_type_name(val)),
+    }
+}
+
+fn seconds_to_duration(sec: f32, path: &str) -> Result<Duration> {
+    Duration::try_from_secs_f32(sec).map_err(|_| anyhow!("{}: {} seconds is too long", path, sec))
+}
+
+fn read_regex(val: &JsonValue, path: &str) -> Result<Option<Regex>> {
+    match read_str(val, path)? {
+        Some(tmp) => Ok(Some(Regex::new(tmp).map_err(|e| anyhow!("{}: invalid regular expression: {}", path, e))?)),
+        None => Ok(None)
+    }
+}
+
+/// Read string or array of strings.
+fn read_str_list<'a>(val: &'a JsonValue, path: &str) -> Result<Vec<(&'a str, String)>> {
+    if val.is_null() {
+        Ok(vec![])
+    } else if let Some(tmp) = val.as_str() {
+        Ok(vec![(tmp, path.to_string())])
+    } else if val.is_array() {
+        val.members().enumerate()
+            .map(|(i, v)| Ok((read_str(v, &index_path(path, i))?.ok_or(anyhow!("{}: must be a string, not null", index_path(path, i)))?, index_path(path, i))))
+            .collect()
+    } else {
+        bail!("{}: must be a string or array of strings, not {}", path, json_type_name(val))
+    }
+}
+
+/// Read days of week like `"mon"`, `"Tuesday"` or ranges like `"mon-fri"`.
+fn read_weekdays(val: &JsonValue, path: &str) -> Result<Vec<Weekday>> {
+    let mut weekdays = Vec::new();
+
+    for (s, item_path) in read_str_list(val, path)? {
+        let parse = |t: &str| t.trim().parse::<Weekday>()
+            .map_err(|_| anyhow!("{}: invalid day of week {:?}", item_path, t));
+
+        match s.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (parse(first)?, parse(last)?);
+                let mut day = first;
+                loop {
+                    weekdays.push(day);
+                    if day == last {
+                        break;
+                    }
+                    day = day.succ();
+                }
+            }
+            None => weekdays.push(parse(s)?),
+        }
+    }
+
+    Ok(weekdays)
+}
+
+/// Read time of day ranges like `"09:00-17:00"`.
+fn read_time_of_day(val: &JsonValue, path: &str) -> Result<Vec<TimeOfDayRange>> {
+    read_str_list(val, path)?.into_iter()
+        .map(|(s, item_path)| TimeOfDayRange::parse(s).map_err(|e| anyhow!("{}: {}", item_path, e)))
+        .collect()
+}
+
+/// Read idle range like `{"min_sec": 300}` or `{"min_sec": 60, "max_sec": 300}`.
+fn read_idle_range(val: &JsonValue, path: &str) -> Result<Option<IdleRange>> {
+    if val.is_null() {
+        return Ok(None);
+    }
+    if !val.is_object() {
+        bail!("{}: must be an object with min_sec and/or max_sec, not {}", path, json_type_name(val));
+    }
+    check_keys(val, path, &IDLE_RANGE_KEYS)?;
+
+    let min = read_duration_sec(&val["min_sec"], &child_path(path, "min_sec"))?;
+    let max = read_duration_sec(&val["max_sec"], &child_path(path, "max_sec"))?;
+    match (min, max) {
+        (None, None) => bail!("{}: must define at least one of {}", path, IDLE_RANGE_KEYS.join(", ")),
+        (Some(min), Some(max)) if min >= max => bail!("{}: min_sec must be less than max_sec", path),
+        _ => Ok(Some(IdleRange { min, max })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{start_time, TestDir};
+    use crate::watcher::clock::SimulatedClock;
+
+    fn window_event(title: &str, path: &str) -> ActiveWindowEvent {
+        let clock = SimulatedClock::new(start_time(), false);
+        ActiveWindowEvent::new(&clock, Duration::from_secs(3), title.to_string(), PathBuf::from(path), Duration::from_secs(15))
+    }
+
+    fn matcher(s: &str) -> WindowEventMatcher {
+        WindowEventMatcher::from_json_single(&json::parse(s).unwrap(), "m").unwrap()
+    }
+
+    #[test]
+    fn process_name_needs_file_name() {
+        let m = matcher(r#"{"process_name": ".*"}"#);
+        assert!(m.matches(&window_event("Inbox", "/usr/bin/thunderbird")));
+        assert!(!m.matches(&window_event("Inbox", "")));
+
+        let not = matcher(r#"{"not": {"process_name": "^thunderbird$"}}"#);
+        assert!(!not.matches(&window_event("Inbox", "/usr/bin/thunderbird")));
+        assert!(not.matches(&window_event("Inbox", "/")));
+        assert!(not.matches(&window_event("Inbox", "/usr/bin/firefox")));
+    }
+
+    #[test]
+    fn nested_all_any_not() {
+        let m = matcher(r#"{
+            "all": [{"process_name": "^(firefox|chromium)$"}, {"not": {"window_title": "Private"}}],
+            "any": [{"window_title": "GitHub"}, {"window_title": "Docs", "not": {"window_title": "Google"}}]
+        }"#);
+        assert!(m.matches(&window_event("GitHub - firefox", "/usr/bin/firefox")));
+        assert!(m.matches(&window_event("Rust Docs", "/usr/bin/chromium")));
+        assert!(!m.matches(&window_event("Google Docs", "/usr/bin/chromium")));
+        assert!(!m.matches(&window_event("GitHub (Private)", "/usr/bin/firefox")));
+        assert!(!m.matches(&window_event("GitHub", "/usr/bin/thunderbird")));
+        assert!(!m.matches(&window_event("News", "/usr/bin/firefox")));
+    }
+
+    #[test]
+    fn invalid_matchers_are_errors() {
+        let error = |s: &str| WindowEventMatcher::from_json_single(&json::parse(s).unwrap(), "tags.work")
+            .unwrap_err().to_string();
+        assert!(error(r#"{}"#).contains("must define at least one of"));
+        assert!(error(r#"[]"#).contains("tags.work: WindowEventMatcher definition should be an object, not array"));
+        assert!(error(r#"{"window_title": "("}"#).starts_with("tags.work.window_title: invalid regular expression"));
+        assert!(error(r#"{"window_title": 1}"#).contains("tags.work.window_title: must be a string, not number"));
+        assert!(error(r#"{"any": []}"#).contains("tags.work.any: must be a non-empty array"));
+        assert!(error(r#"{"all": [{"not": {"window_title": "("}}]}"#).starts_with("tags.work.all[0].not.window_title: invalid regular expression"));
+        assert!(error(r#"{"not": [{"window_title": "a"}]}"#).contains("tags.work.not: must be a WindowEventMatcher object"));
+        assert_eq!(error(r#"{"window_tilte": "a"}"#), r#"tags.work.window_tilte: unknown key, did you mean "window_title"?"#);
+        assert!(error(r#"{"colour": "a"}"#).contains("unknown key, expected one of window_title,"));
+        assert!(error(r#"{"weekday": "someday"}"#).contains("invalid day of week"));
+        assert!(error(r#"{"time_of_day": "9-17"}"#).contains("invalid time"));
+        assert!(error(r#"{"idle_for": {"min_sec": 300, "max_sec": 60}}"#).contains("min_sec must be less than max_sec"));
+        assert!(error(r#"{"idle_for": {"max_sec": -1}}"#).contains("must not be negative"));
+        assert!(error(r#"{"idle_for": {"min_sec": 1e30}}"#).contains("too long"));
+    }
+
+    #[test]
+    fn time_of_day_ranges() {
+        let t = |s: &str| NaiveTime::parse_from_str(s, "%H:%M").unwrap();
+
+        let day = TimeOfDayRange::parse("09:00-17:30").unwrap();
+        assert!(day.contains(t("09:00")) && day.contains(t("17:29")));
+        assert!(!day.contains(t("08:59")) && !day.contains(t("17:30")));
+
+        let evening = TimeOfDayRange::parse("18:00-24:00").unwrap();
+        assert!(evening.contains(t("18:00")) && evening.contains(t("23:59")));
+        assert!(!evening.contains(t("00:00")) && !evening.contains(t("17:59")));
+
+        let night = TimeOfDayRange::parse("22:00 - 06:00").unwrap();
+        assert!(night.contains(t("23:00")) && night.contains(t("00:00")) && night.contains(t("05:59")));
+        assert!(!night.contains(t("06:00")) && !night.contains(t("21:59")));
+
+        assert!(TimeOfDayRange::parse("00:00-24:00").unwrap().contains(t("12:00")));
+        assert!(TimeOfDayRange::parse("24:01-25:00").is_err());
+        assert!(TimeOfDayRange::parse("09:00").is_err());
+    }
+
+    #[test]
+    fn weekdays_and_time_of_day_use_local_time() {
+        let e = window_event("Inbox", "/usr/bin/thunderbird");
+        let local_time = e.time.with_timezone(&Local);
+        let today = local_time.weekday();
+        let hour = local_time.hour();
+
+        let m = |s: String| matcher(&s);
+        assert!(m(format!(r#"{{"weekday": "{}"}}"#, today)).matches(&e));
+        assert!(!m(format!(r#"{{"weekday": ["{}", "{}"]}}"#, today.succ(), today.pred())).matches(&e));
+        assert!(m(format!(r#"{{"weekday": "{}-{}"}}"#, today.pred(), today.succ())).matches(&e));
+        assert!(!m(format!(r#"{{"weekday": "{}-{}"}}"#, today.succ(), today.pred())).matches(&e));
+        assert!(m(format!(r#"{{"time_of_day": "{:02}:00-{:02}:00"}}"#, hour, (hour + 1) % 24)).matches(&e));
+        assert!(m(format!(r#"{{"time_of_day": "{:02}:00-24:00"}}"#, hour)).matches(&e));
+        assert!(!m(format!(r#"{{"time_of_day": "{:02}:00-{:02}:00"}}"#, (hour + 1) % 24, (hour + 2) % 24)).matches(&e));
+    }
+
+    #[test]
+    fn weekday_ranges_wrap_around() {
+        let weekdays = read_weekdays(&json::parse(r#"["sat-mon", "Wednesday"]"#).unwrap(), "weekday").unwrap();
+        assert_eq!(weekdays, vec![Weekday::Sat, Weekday::Sun, Weekday::Mon, Weekday::Wed]);
+    }
+
+    #[test]
+    fn rewrite_rules() {
+        let rules = RewriteRule::from_json(&json::parse(r#"[
+            {"pattern": " - Mozilla Firefox$", "replacement": ""},
+            {"match": {"process_name": "^code$"}, "pattern": "^(.*) - (\\w+) - Visual Studio Code$", "replacement": "$2: $1"}
+        ]"#).unwrap(), "rewrite").unwrap();
+
+        let rewrite = |title: &str, path: &str| {
+            let mut e = window_event(title, path);
+            rules.iter().for_each(|r| r.apply(&mut e));
+            e.window_title
+        };
+        assert_eq!(rewrite("News - Mozilla Firefox", "/usr/bin/firefox"), "News");
+        assert_eq!(rewrite("main.rs - moonwatch - Visual Studio Code", "/usr/bin/code"), "moonwatch: main.rs");
+        assert_eq!(rewrite("main.rs - moonwatch - Visual Studio Code", "/usr/bin/codium"), "main.rs - moonwatch - Visual Studio Code");
+
+        let error = |s: &str| RewriteRule::from_json(&json::parse(s).unwrap(), "rewrite").unwrap_err().to_string();
+        assert_eq!(error(r#"{"pattern": "a"}"#), "rewrite.replacement: missing");
+        assert!(error(r#"[{"pattern": "(", "replacement": ""}]"#).starts_with("rewrite[0].pattern: invalid regular expression"));
+        assert_eq!(error(r#"{"pattern": "a", "replacment": ""}"#), r#"rewrite.replacment: unknown key, did you mean "replacement"?"#);
+    }
+
+    #[test]
+    fn idle_for_ranges() {
+        // window_event() is idle for 3 seconds
+        let e = window_event("Inbox", "/usr/bin/thunderbird");
+        assert!(matcher(r#"{"idle_for": {"min_sec": 3}}"#).matches(&e));
+        assert!(matcher(r#"{"idle_for": {"min_sec": 1, "max_sec": 5}}"#).matches(&e));
+        assert!(!matcher(r#"{"idle_for": {"max_sec": 3}}"#).matches(&e));
+        assert!(!matcher(r#"{"idle_for": {"min_sec": 60}}"#).matches(&e));
+    }
+
+    #[test]
+    fn anonymize_rules() {
+        let rules = AnonymizeRule::from_json(&json::parse(r#"[
+            {"window_title": "Private"},
+            {"match": {"process_name": "^(firefox|thunderbird)$"}, "mode": "hash"}
+        ]"#).unwrap(), "anonymize").unwrap();
+        assert_eq!(rules.len(), 2);
+        let mode = |title: &str, path: &str| rules.iter()
+            .filter(|r| r.matcher.matches(&window_event(title, path)))
+            .map(|r| r.mode)
+            .max();
+        assert_eq!(mode("News", "/usr/bin/firefox"), Some(AnonymizeMode::Hash));
+        assert_eq!(mode("Private Browsing", "/usr/bin/firefox"), Some(AnonymizeMode::Redact));
+        assert_eq!(mode("Private Browsing", "/usr/bin/chromium"), Some(AnonymizeMode::Redact));
+        assert_eq!(mode("News", "/usr/bin/chromium"), None);
+
+        let error = AnonymizeRule::from_json(&json::parse(r#"{"match": {"window_title": "a"}, "mode": "scramble"}"#).unwrap(), "anonymize");
+        assert!(error.unwrap_err().to_string().starts_with("anonymize.mode: "));
+    }
+
+    #[test]
+    fn anonymize_section() {
+        let dir = TestDir::new("config-anonymize");
+        let path = dir.write("config.json", r#"{
+            "main": {"output_dir": "log", "sample_every_sec": 15, "write_every_sec": 300, "anonymize_key_file": "key.txt"},
+            "anonymize": {"match": {"process_name": "^firefox$"}, "mode": "hash"}
+        }"#);
+        let config = Config::validate_file(&path).unwrap();
+        assert!(config.uses_anonymize_key());
+        assert!(config.redact_log());
+        assert_eq!(config.anonymize_key_path, dir.join("key.txt"));
+        assert_eq!(config.anonymize_mode(&window_event("News", "/usr/bin/firefox")), Some(AnonymizeMode::Hash));
+        assert_eq!(config.anonymize_mode(&window_event("News", "/usr/bin/chromium")), None);
+    }
+
+    fn write_base(dir: &TestDir, name: &str, tag: &str, base: &str) -> PathBuf {
+        dir.write(name, format!(r#"{{"main": {{"path_to_base_config": {}}}, "tags": {{"{}": {{"window_title": "{}"}}}}}}"#, base, tag, tag))
+    }
+
+    fn write_main(dir: &TestDir, base: &str) -> PathBuf {
+        dir.write("config.json", format!(r#"{{"main": {{"output_dir": "log", "sample_every_sec": 15, "write_every_sec": 300, "path_to_base_config": {}}}}}"#, base))
+    }
+
+    fn write_config(name: &str, main: &str) -> Result<Config> {
+        let dir = TestDir::new(&format!("config-{}", name));
+        let path = dir.write("config.json", format!(r#"{{
+            "main": {{"output_dir": "log", "sample_every_sec": 15, "write_every_sec": 300{}}}
+        }}"#, main));
+        Config::validate_file(&path)
+    }
+
+    #[test]
+    fn main_section() {
+        let config = write_config("main", r#", "afk_after_sec": 300, "compression": "zstd""#).unwrap();
+        assert_eq!(config.sample_every, Duration::from_secs(15));
+        assert_eq!(config.write_every, Duration::from_secs(300));
+        assert_eq!(config.afk_after, Some(Duration::from_secs(300)));
+        assert_eq!(config.compression, Compression::Zstd);
+        assert!(config.reload_on_change);
+        assert!(!config.coalesce);
+
+        let error = |name: &str, main: &str| format!("{:#}", write_config(name, main).unwrap_err());
+        assert!(error("unknown", r#", "afk_afer_sec": 1"#).contains(r#"main.afk_afer_sec: unknown key, did you mean "afk_after_sec"?"#));
+        assert!(error("huge", r#", "sample_every_sec": 1e30"#).contains("main.sample_every_sec: 1000000000000000000000000000000 seconds is too long"));
+        assert!(error("huge-afk", r#", "afk_after_sec": 1e30"#).contains("main.afk_after_sec: 1000000000000000000000000000000 seconds is too long"));
+        assert!(error("zero", r#", "write_every_sec": 0"#).contains("main.write_every_sec: must be a positive number"));
+        assert!(error("type", r#", "coalesce": "yes""#).contains("main.coalesce: must be a boolean, not string"));
+        assert!(error("missing", r#", "output_dir": null"#).contains("main.output_dir: missing"));
+    }
+
+    fn tag_names(config: &Config) -> Vec<&str> {
+        config.tags.iter().map(|t| t.tag.as_str()).collect()
+    }
+
+    #[test]
+    fn base_configs_are_merged_depth_first() {
+        let dir = TestDir::new("config-chain");
+        write_base(&dir, "team.json", "team", r#""org.json""#);
+        write_base(&dir, "org.json", "org", "null");
+        write_base(&dir, "machine.json", "machine", r#"["org.json"]"#);
+        let path = write_main(&dir, r#"["team.json", "machine.json", {"path": "missing.json", "required": false}]"#);
+
+        let config = Config::validate_file(&path).unwrap();
+        assert_eq!(tag_names(&config), vec!["team", "org", "machine"]);
+        assert_eq!(config.base_config_paths.len(), 4);
+    }
+
+    #[test]
+    fn base_config_cycles_are_errors() {
+        let dir = TestDir::new("config-cycle");
+        write_base(&dir, "a.json", "a", r#""b.json""#);
+        write_base(&dir, "b.json", "b", r#""a.json""#);
+        let path = write_main(&dir, r#""a.json""#);
+        let error = format!("{:#}", Config::validate_file(&path).unwrap_err());
+        assert!(error.contains("base configs include each other"), "{}", error);
+        assert!(error.contains(r#"a.json" -> "#) && error.contains(r#"b.json" -> "#), "{}", error);
+
+        let dir = TestDir::new("config-self-cycle");
+        let path = write_main(&dir, r#""config.json""#);
+        assert!(format!("{:#}", Config::validate_file(&path).unwrap_err()).contains("base configs include each other"));
+    }
+
+    #[test]
+    fn cached_base_config_is_used_when_missing_or_invalid() {
+        let dir = TestDir::new("config-cache");
+        let base_path = write_base(&dir, "base.json", "base", "null");
+        let path = write_main(&dir, r#""base.json""#);
+
+        // check-config does not update the cache
+        Config::validate_file(&path).unwrap();
+        fs::write(&base_path, "{\"tags\": {").unwrap();
+        assert!(format!("{:#}", Config::validate_file(&path).unwrap_err()).contains("failed to read required base_config"));
+
+        write_base(&dir, "base.json", "base", "null");
+        let config = Config::from_file(&path).unwrap();
+        assert_eq!(tag_names(&config), vec!["base"]);
+        let hash = config.hash;
+
+        // half-synced
+        fs::write(&base_path, "{\"tags\": {").unwrap();
+        let config = Config::from_file(&path).unwrap();
+        assert_eq!(tag_names(&config), vec!["base"]);
+        assert_eq!(config.hash, hash);
+
+        // valid but not a base config
+        fs::write(&base_path, "[]").unwrap();
+        assert_eq!(tag_names(&Config::from_file(&path).unwrap()), vec!["base"]);
 
-        let tags = Config::read_tags(&d["tags"])?;
-        let ignore = WindowEventMatcher::from_json(&d["ignore"])?;
-        let anonymize = WindowEventMatcher::from_json(&d["anonymize"])?;
+        fs::remove_file(&base_path).unwrap();
+        assert_eq!(tag_names(&Config::validate_file(&path).unwrap()), vec!["base"]);
 
-        Ok(BaseConfig { tags, ignore, anonymize })
+        // the invalid copy did not replace the cached one
+        write_base(&dir, "base.json", "new", "null");
+        assert_eq!(tag_names(&Config::from_file(&path).unwrap()), vec!["new"]);
+        fs::remove_file(&base_path).unwrap();
+        assert_eq!(tag_names(&Config::from_file(&path).unwrap()), vec!["new"]);
     }
 }
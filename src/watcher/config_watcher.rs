@@ -0,0 +1,143 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use crate::watcher::core::MoonwatcherSignal;
+
+/// Sends `MoonwatcherSignal::ReloadConfig` when any of the watched configuration files changes.
+///
+/// Parent directories of the files are watched rather than the files themselves, so that changes
+/// made by replacing the file (as done by many editors and sync clients) are noticed as well.
+/// Changes are debounced: the signal is sent once no further change came for the debounce period.
+pub struct ConfigWatcher {
+    // watching stops when this is dropped
+    _watcher: Option<RecommendedWatcher>,
+    receiver: Receiver<MoonwatcherSignal>,
+}
+
+impl ConfigWatcher {
+    pub fn spawn(paths: &[PathBuf], debounce: Duration) -> Result<ConfigWatcher> {
+        let watched_paths: Vec<PathBuf> = paths.iter().map(|p| normalize_path(p)).collect();
+        let (event_sender, event_receiver) = crossbeam_channel::unbounded();
+        let mut watcher = notify::recommended_watcher(move |res| {
+            // the receiving thread is gone once the watcher is being dropped
+            let _ = event_sender.send(res);
+        })?;
+
+        let mut watched_dirs = Vec::<PathBuf>::new();
+        for path in &watched_paths {
+            let dir = match path.parent() {
+                Some(dir) => dir.to_path_buf(),
+                None => continue,
+            };
+            if watched_dirs.contains(&dir) {
+                continue;
+            }
+            match watcher.watch(&dir, RecursiveMode::NonRecursive) {
+                Ok(_) => watched_dirs.push(dir),
+                Err(e) => println!("Cannot watch {:?} for configuration changes: {:?}", dir, e),
+            }
+        }
+
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        thread::spawn(move || {
+            loop {
+                // wait for the first relevant change
+                match event_receiver.recv() {
+                    Ok(Ok(event)) if is_relevant(&event, &watched_paths) => {}
+                    Ok(Ok(_)) => continue,
+                    Ok(Err(e)) => {
+                        println!("Error while watching configuration files: {:?}", e);
+                        continue;
+                    }
+                    Err(_) => break,
+                }
+
+                // wait until things settle down
+                let mut deadline = Instant::now() + debounce;
+                loop {
+                    match event_receiver.recv_deadline(deadline) {
+                        Ok(Ok(event)) if is_relevant(&event, &watched_paths) => deadline = Instant::now() + debounce,
+                        Ok(_) => continue,
+                        Err(RecvTimeoutError::Timeout) => break,
+                        Err(RecvTimeoutError::Disconnected) => return,
+                    }
+                }
+
+                println!("Configuration file changed");
+                // if a reload is already pending, there is no need for another one
+                let _ = sender.try_send(MoonwatcherSignal::ReloadConfig);
+            }
+        });
+
+        Ok(ConfigWatcher { _watcher: Some(watcher), receiver })
+    }
+
+    /// Watcher that never sends anything.
+    pub fn disabled() -> ConfigWatcher {
+        ConfigWatcher { _watcher: None, receiver: crossbeam_channel::never() }
+    }
+
+    pub fn receiver(&self) -> &Receiver<MoonwatcherSignal> {
+        &self.receiver
+    }
+}
+
+fn is_relevant(event: &notify::Event, watched_paths: &[PathBuf]) -> bool {
+    if event.kind.is_access() {
+        return false;
+    }
+    event.paths.iter().any(|p| watched_paths.contains(&normalize_path(p)))
+}
+
+/// Absolute path with symlinks resolved in the directory part, so that the same file compares equal
+/// whether it exists or not (eg. while it is being replaced).
+fn normalize_path(path: &Path) -> PathBuf {
+    let absolute_path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    match (absolute_path.parent(), absolute_path.file_name()) {
+        (Some(dir), Some(name)) => fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf()).join(name),
+        _ => absolute_path,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestDir;
+
+    const DEBOUNCE: Duration = Duration::from_millis(500);
+
+    #[test]
+    fn changes_are_debounced() {
+        let dir = TestDir::new("config-watcher");
+        let path = dir.write("config.json", "{}");
+
+        let config_watcher = ConfigWatcher::spawn(std::slice::from_ref(&path), DEBOUNCE).unwrap();
+        let receiver = config_watcher.receiver();
+
+        // other files in the directory are not watched
+        fs::write(dir.join("other.json"), "{}").unwrap();
+        assert!(receiver.recv_timeout(2 * DEBOUNCE).is_err());
+
+        // a burst of changes gives a single reload, once the changes settle
+        for i in 0..5 {
+            thread::sleep(DEBOUNCE / 5);
+            fs::write(&path, format!("{{\"i\": {}}}", i)).unwrap();
+        }
+        let last_change = Instant::now();
+        assert!(matches!(receiver.recv_timeout(10 * DEBOUNCE), Ok(MoonwatcherSignal::ReloadConfig)));
+        assert!(last_change.elapsed() >= DEBOUNCE);
+        assert!(receiver.recv_timeout(2 * DEBOUNCE).is_err());
+
+        // replacing the file counts as a change
+        let tmp_path = dir.join("config.json.tmp");
+        fs::write(&tmp_path, "{}").unwrap();
+        fs::rename(&tmp_path, &path).unwrap();
+        assert!(matches!(receiver.recv_timeout(10 * DEBOUNCE), Ok(MoonwatcherSignal::ReloadConfig)));
+
+        drop(config_watcher);
+    }
+}
@@ -1,9 +1,23 @@
-use std::collections::LinkedList;
-use std::path::PathBuf;
+use std::collections::{HashMap, LinkedList};
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 use chrono::{DateTime, Utc};
 use json;
-use anyhow::Result;
+use json::JsonValue;
+use anyhow::{anyhow, bail, Result};
+use crate::watcher::clock::Clock;
+use crate::watcher::encryption;
+
+/// Version of the event format written by `ActiveWindowEvent::to_json`.
+///
+/// - version 1 (implicit, no `"schema_version"` key): no `"window_title"`
+/// - version 2: adds `"schema_version"` and `"window_title"` (null for anonymized events)
+/// - version 3: adds `"status"`
+/// - version 4: adds `"end"` (events may be spans of merged samples, see `coalesce`)
+/// - version 5: adds `SessionEvent` types (`"LockEvent"`, `"DaemonStart"`, ...) next to `"ActiveWindowEvent"`
+/// - version 6: adds `"Suspend"` and `"Resume"` session events, `"duration"` is measured instead of assumed
+/// - version 7: adds `"ConfigReload"` session event
+pub const SCHEMA_VERSION: u32 = 7;
 
 pub trait Window {
     fn get_title(&self) -> Result<String>;
@@ -22,15 +36,49 @@ pub trait Desktop {
     fn before_main_loop_start(&self) -> Result<()> {
         Ok(())
     }
+    /// Whether there is nothing more to sample and the daemon should exit (eg. end of scripted timeline).
+    fn is_finished(&self) -> bool {
+        false
+    }
 }
 
-#[derive(Debug)]
+/// Whether the user was at the computer when the event was sampled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventStatus {
+    Active,
+    /// Idle for longer than `afk_after_sec` from the config.
+    Afk,
+}
+
+impl EventStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventStatus::Active => "active",
+            EventStatus::Afk => "afk",
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<EventStatus> {
+        match s {
+            "active" => Ok(EventStatus::Active),
+            "afk" => Ok(EventStatus::Afk),
+            _ => bail!("unknown event status {:?}", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct ActiveWindowEvent {
+    /// Time of the (first) sample.
     pub time: DateTime::<Utc>,
+    /// Time of the last sample merged into this event, same as `time` for a single sample.
+    pub end: DateTime::<Utc>,
+    /// Total duration of all samples in this event.
     pub duration: Duration,
     pub hostname: String,
     pub username: String,
     pub idle_for: Duration,
+    pub status: EventStatus,
     pub window_title: String,
     pub process_path: PathBuf,
     pub tags: LinkedList<String>,
@@ -38,16 +86,20 @@ pub struct ActiveWindowEvent {
 }
 
 impl ActiveWindowEvent {
-    pub fn new(idle_for: Duration,
+    pub fn new(clock: &dyn Clock,
+               idle_for: Duration,
                window_title: String,
                process_path: PathBuf,
                duration: Duration) -> ActiveWindowEvent {
+        let time = clock.now();
         ActiveWindowEvent {
-            time: Utc::now(),
+            time,
+            end: time,
             duration,
             hostname: whoami::hostname(),
             username: whoami::username(),
             idle_for,
+            status: EventStatus::Active,
             window_title,
             process_path,
             tags: LinkedList::new(),
@@ -56,32 +108,378 @@ impl ActiveWindowEvent {
     }
 
     pub fn to_json(&self) -> json::JsonValue {
-        let tags: Vec<String> = self.tags.iter().map(|x| String::from(x)).collect();
+        let tags: Vec<String> = self.tags.iter().map(String::from).collect();
 
         if self.anonymize {
             json::object! {
                 "type": "ActiveWindowEvent",
+                "schema_version": SCHEMA_VERSION,
                 "time": self.time.to_rfc3339(),
+                "end": self.end.to_rfc3339(),
                 "duration": self.duration.as_secs_f32().round(),
                 "hostname": self.hostname.as_str(),
                 "username": self.username.as_str(),
                 "idle_for": self.idle_for.as_secs_f32().round(),
+                "status": self.status.as_str(),
+                "window_title": json::Null,
                 "process_path": json::Null,
                 "tags": tags,
             }
         } else {
             json::object! {
                 "type": "ActiveWindowEvent",
+                "schema_version": SCHEMA_VERSION,
                 "time": self.time.to_rfc3339(),
+                "end": self.end.to_rfc3339(),
                 "duration": self.duration.as_secs_f32().round(),
                 "hostname": self.hostname.as_str(),
                 "username": self.username.as_str(),
                 "idle_for": self.idle_for.as_secs_f32().round(),
+                "status": self.status.as_str(),
+                "window_title": self.window_title.as_str(),
                 "process_path": self.process_path.to_str().unwrap_or(""),
                 "tags": tags,
             }
         }
     }
+
+    /// Parse event written by `to_json`, accepting all schema versions up to `SCHEMA_VERSION`.
+    ///
+    /// Events from logs without `"window_title"` get an empty title, events without `"status"`
+    /// are read as active, events without `"end"` are single samples. Events with null
+    /// `"process_path"` are read back as anonymized.
+    pub fn from_json(val: &JsonValue) -> Result<ActiveWindowEvent> {
+        if !val.is_object() {
+            bail!("ActiveWindowEvent JSON should be an object, not {:?}", val);
+        }
+
+        if val["type"].as_str() != Some("ActiveWindowEvent") {
+            bail!("unexpected event type {:?}", val["type"]);
+        }
+        check_schema_version(val)?;
+
+        let time = read_time(&val["time"]).ok_or(anyhow!("cannot read time"))??;
+        let end = read_time(&val["end"]).transpose()?.unwrap_or(time);
+        let read_duration = |key: &str| -> Result<Duration> {
+            let secs = val[key].as_f64().ok_or(anyhow!("cannot read {}", key))?;
+            Duration::try_from_secs_f64(secs).map_err(|e| anyhow!("invalid {} {}: {}", key, secs, e))
+        };
+        let duration = read_duration("duration")?;
+        let idle_for = read_duration("idle_for")?;
+        let status = match val["status"].as_str() {
+            Some(tmp) => EventStatus::parse(tmp)?,
+            None => EventStatus::Active,
+        };
+        let hostname = val["hostname"].as_str().ok_or(anyhow!("cannot read hostname"))?.to_string();
+        let username = val["username"].as_str().ok_or(anyhow!("cannot read username"))?.to_string();
+        let window_title = val["window_title"].as_str().unwrap_or_default().to_string();
+        let anonymize = val["process_path"].is_null();
+        let process_path = PathBuf::from(val["process_path"].as_str().unwrap_or_default());
+
+        let mut tags = LinkedList::new();
+        for tag in val["tags"].members() {
+            tags.push_back(tag.as_str().ok_or(anyhow!("tags must be strings, not {:?}", tag))?.to_string());
+        }
+
+        Ok(ActiveWindowEvent {
+            time,
+            end,
+            duration,
+            hostname,
+            username,
+            idle_for,
+            status,
+            window_title,
+            process_path,
+            tags,
+            anonymize,
+        })
+    }
+
+    /// Whether `next` continues this event, ie. it is the same window with the same tags etc.
+    /// and it was sampled at most `max_gap` after the last sample of this event.
+    pub fn can_merge(&self, next: &ActiveWindowEvent, max_gap: Duration) -> bool {
+        self.hostname == next.hostname
+            && self.username == next.username
+            && self.window_title == next.window_title
+            && self.process_path == next.process_path
+            && self.tags == next.tags
+            && self.anonymize == next.anonymize
+            && self.status == next.status
+            && next.time >= self.end
+            && (next.time - self.end).to_std().is_ok_and(|gap| gap <= max_gap)
+    }
+
+    /// Extend this event by `next` (see `can_merge`); `idle_for` of the result is the larger of the two.
+    pub fn merge(&mut self, next: ActiveWindowEvent) {
+        self.end = next.end;
+        self.duration += next.duration;
+        self.idle_for = self.idle_for.max(next.idle_for);
+    }
+
+    /// Read all `ActiveWindowEvent`s from a `.jsonl` file as written by the `moonwatcher` daemon,
+    /// skipping other event types (see `Event::read_jsonl` for all of them).
+    ///
+    /// Compressed files (`.jsonl.gz`, `.jsonl.zst`) are decompressed transparently.
+    /// For encrypted files, use `read_jsonl_with_identities`.
+    pub fn read_jsonl(path: &Path) -> Result<Vec<ActiveWindowEvent>> {
+        ActiveWindowEvent::read_jsonl_with_identities(path, &[])
+    }
+
+    /// Like `read_jsonl`, also decrypting files encrypted to one of the identities.
+    pub fn read_jsonl_with_identities(path: &Path, identities: &[Box<dyn age::Identity>]) -> Result<Vec<ActiveWindowEvent>> {
+        Ok(Event::read_jsonl_with_identities(path, identities)?.into_iter()
+            .filter_map(|e| match e {
+                Event::ActiveWindow(e) => Some(e),
+                Event::Session(_) => None,
+            })
+            .collect())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionEventKind {
+    /// Screen was locked, nothing is sampled until `Unlock`.
+    Lock,
+    Unlock,
+    /// User became idle for longer than `afk_after_sec` (see `EventStatus::Afk`).
+    AfkStart,
+    /// User is back, or locked the screen (which is recorded as `Lock` instead).
+    AfkEnd,
+    /// The daemon started.
+    DaemonStart(DaemonInfo),
+    /// The daemon is stopping; if it is missing, the daemon was killed or the machine lost power.
+    DaemonStop(DaemonInfo),
+    /// The daemon reloaded its configuration, with the hash of the new one.
+    ConfigReload(DaemonInfo),
+    /// The machine was suspended at some point after this time, which is the last sample before it.
+    Suspend,
+    /// The machine was resumed from suspend, detected at the first sample after it.
+    Resume,
+}
+
+/// Daemon version and configuration, recorded with the daemon's own events.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DaemonInfo {
+    pub version: String,
+    /// Identifies the configuration the daemon runs with.
+    pub config_hash: String,
+}
+
+impl SessionEventKind {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            SessionEventKind::Lock => "LockEvent",
+            SessionEventKind::Unlock => "UnlockEvent",
+            SessionEventKind::AfkStart => "AfkStart",
+            SessionEventKind::AfkEnd => "AfkEnd",
+            SessionEventKind::DaemonStart(_) => "DaemonStart",
+            SessionEventKind::DaemonStop(_) => "DaemonStop",
+            SessionEventKind::ConfigReload(_) => "ConfigReload",
+            SessionEventKind::Suspend => "Suspend",
+            SessionEventKind::Resume => "Resume",
+        }
+    }
+}
+
+/// Change of state of the user session or the daemon, which tells "not tracked" from "away" in the logs.
+#[derive(Debug, Clone)]
+pub struct SessionEvent {
+    pub time: DateTime::<Utc>,
+    pub hostname: String,
+    pub username: String,
+    pub kind: SessionEventKind,
+}
+
+impl SessionEvent {
+    pub fn new(clock: &dyn Clock, kind: SessionEventKind) -> SessionEvent {
+        SessionEvent {
+            time: clock.now(),
+            hostname: whoami::hostname(),
+            username: whoami::username(),
+            kind,
+        }
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        let mut val = json::object! {
+            "type": self.kind.type_name(),
+            "schema_version": SCHEMA_VERSION,
+            "time": self.time.to_rfc3339(),
+            "hostname": self.hostname.as_str(),
+            "username": self.username.as_str(),
+        };
+        match &self.kind {
+            SessionEventKind::DaemonStart(info)
+            | SessionEventKind::DaemonStop(info)
+            | SessionEventKind::ConfigReload(info) => {
+                val["version"] = info.version.as_str().into();
+                val["config_hash"] = info.config_hash.as_str().into();
+            }
+            _ => {}
+        }
+        val
+    }
+
+    pub fn from_json(val: &JsonValue) -> Result<SessionEvent> {
+        if !val.is_object() {
+            bail!("SessionEvent JSON should be an object, not {:?}", val);
+        }
+        check_schema_version(val)?;
+
+        let read_str = |key: &str| val[key].as_str().map(String::from).ok_or(anyhow!("cannot read {}", key));
+        let read_info = || -> Result<DaemonInfo> {
+            Ok(DaemonInfo { version: read_str("version")?, config_hash: read_str("config_hash")? })
+        };
+        let kind = match val["type"].as_str() {
+            Some("LockEvent") => SessionEventKind::Lock,
+            Some("UnlockEvent") => SessionEventKind::Unlock,
+            Some("AfkStart") => SessionEventKind::AfkStart,
+            Some("AfkEnd") => SessionEventKind::AfkEnd,
+            Some("DaemonStart") => SessionEventKind::DaemonStart(read_info()?),
+            Some("DaemonStop") => SessionEventKind::DaemonStop(read_info()?),
+            Some("ConfigReload") => SessionEventKind::ConfigReload(read_info()?),
+            Some("Suspend") => SessionEventKind::Suspend,
+            Some("Resume") => SessionEventKind::Resume,
+            _ => bail!("unexpected event type {:?}", val["type"]),
+        };
+
+        Ok(SessionEvent {
+            time: read_time(&val["time"]).ok_or(anyhow!("cannot read time"))??,
+            hostname: read_str("hostname")?,
+            username: read_str("username")?,
+            kind,
+        })
+    }
+}
+
+/// Any event written by the `moonwatcher` daemon.
+#[derive(Debug, Clone)]
+pub enum Event {
+    ActiveWindow(ActiveWindowEvent),
+    Session(SessionEvent),
+}
+
+impl Event {
+    pub fn time(&self) -> DateTime<Utc> {
+        match self {
+            Event::ActiveWindow(e) => e.time,
+            Event::Session(e) => e.time,
+        }
+    }
+
+    /// Time of the last sample of a span of merged window samples, same as `time` otherwise.
+    pub fn end(&self) -> DateTime<Utc> {
+        match self {
+            Event::ActiveWindow(e) => e.end,
+            Event::Session(e) => e.time,
+        }
+    }
+
+    pub fn hostname(&self) -> &str {
+        match self {
+            Event::ActiveWindow(e) => e.hostname.as_str(),
+            Event::Session(e) => e.hostname.as_str(),
+        }
+    }
+
+    pub fn username(&self) -> &str {
+        match self {
+            Event::ActiveWindow(e) => e.username.as_str(),
+            Event::Session(e) => e.username.as_str(),
+        }
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        match self {
+            Event::ActiveWindow(e) => e.to_json(),
+            Event::Session(e) => e.to_json(),
+        }
+    }
+
+    /// Parse event of any type, see `ActiveWindowEvent::from_json` and `SessionEvent::from_json`.
+    pub fn from_json(val: &JsonValue) -> Result<Event> {
+        match val["type"].as_str() {
+            Some("ActiveWindowEvent") => Ok(Event::ActiveWindow(ActiveWindowEvent::from_json(val)?)),
+            _ => Ok(Event::Session(SessionEvent::from_json(val)?)),
+        }
+    }
+
+    /// Read all events from a `.jsonl` file as written by the `moonwatcher` daemon.
+    ///
+    /// Compressed files (`.jsonl.gz`, `.jsonl.zst`) are decompressed transparently.
+    /// For encrypted files, use `read_jsonl_with_identities`.
+    pub fn read_jsonl(path: &Path) -> Result<Vec<Event>> {
+        Event::read_jsonl_with_identities(path, &[])
+    }
+
+    /// Like `read_jsonl`, also decrypting files encrypted to one of the identities.
+    pub fn read_jsonl_with_identities(path: &Path, identities: &[Box<dyn age::Identity>]) -> Result<Vec<Event>> {
+        let data = encryption::read_to_string(path, identities)?;
+        let mut events = Vec::new();
+
+        for (i, line) in data.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let val = json::parse(line)?;
+            let e = Event::from_json(&val)
+                .map_err(|err| anyhow!("{:?}, line {}: {}", path, i + 1, err))?;
+            events.push(e);
+        }
+
+        Ok(events)
+    }
+}
+
+fn check_schema_version(val: &JsonValue) -> Result<()> {
+    let schema_version = if val["schema_version"].is_null() {
+        1
+    } else {
+        val["schema_version"].as_u32().ok_or(anyhow!("cannot read schema_version"))?
+    };
+    if schema_version > SCHEMA_VERSION {
+        bail!("unsupported schema_version {} (newest supported is {})", schema_version, SCHEMA_VERSION);
+    }
+    Ok(())
+}
+
+/// Read RFC 3339 timestamp, `None` if the value is missing.
+fn read_time(val: &JsonValue) -> Option<Result<DateTime<Utc>>> {
+    val.as_str().map(|tmp| Ok(DateTime::parse_from_rfc3339(tmp)?.with_timezone(&Utc)))
+}
+
+/// Merge consecutive `ActiveWindowEvent`s of the same window into spans, see `ActiveWindowEvent::can_merge`.
+///
+/// Events are expected to be sorted by time; events from different hosts and users may be interleaved,
+/// they are merged separately. A `SessionEvent` (eg. screen lock) ends the current span of its host and user.
+/// The result is sorted by (start) time. This works for already merged events as well, eg. to merge spans
+/// across chunk files read by `store::EventStore::events`.
+pub fn coalesce(events: impl IntoIterator<Item = Event>, max_gap: Duration) -> Vec<Event> {
+    let mut spans: Vec<Event> = Vec::new();
+    // index of the last span of each host and user
+    let mut last_span = HashMap::<(String, String), usize>::new();
+
+    for e in events {
+        let key = (e.hostname().to_string(), e.username().to_string());
+        match e {
+            Event::ActiveWindow(e) => {
+                match last_span.get(&key).map(|&i| &mut spans[i]) {
+                    Some(Event::ActiveWindow(span)) if span.can_merge(&e, max_gap) => span.merge(e),
+                    _ => {
+                        last_span.insert(key, spans.len());
+                        spans.push(Event::ActiveWindow(e));
+                    }
+                }
+            }
+            Event::Session(_) => {
+                last_span.remove(&key);
+                spans.push(e);
+            }
+        }
+    }
+
+    spans
 }
 
 #[derive(Debug)]
@@ -89,3 +487,230 @@ pub enum MoonwatcherSignal {
     ReloadConfig,
     Terminate
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::start_time;
+    use crate::watcher::clock::SimulatedClock;
+
+    fn window_event(clock: &dyn Clock, title: &str, path: &str) -> ActiveWindowEvent {
+        ActiveWindowEvent::new(clock, Duration::from_secs(3), title.to_string(), PathBuf::from(path), Duration::from_secs(15))
+    }
+
+    #[test]
+    fn active_window_event_json_round_trip() {
+        let clock = SimulatedClock::new(start_time(), false);
+        let mut e = window_event(&clock, "Inbox", "/usr/bin/thunderbird");
+        e.status = EventStatus::Afk;
+        e.tags.push_back("mail".to_string());
+
+        let val = e.to_json();
+        assert_eq!(val["schema_version"], SCHEMA_VERSION);
+        let e2 = ActiveWindowEvent::from_json(&json::parse(&val.dump()).unwrap()).unwrap();
+        assert_eq!(e2.time, e.time);
+        assert_eq!(e2.end, e.end);
+        assert_eq!(e2.duration, e.duration);
+        assert_eq!(e2.idle_for, e.idle_for);
+        assert_eq!(e2.status, EventStatus::Afk);
+        assert_eq!(e2.hostname, e.hostname);
+        assert_eq!(e2.username, e.username);
+        assert_eq!(e2.window_title, "Inbox");
+        assert_eq!(e2.process_path, PathBuf::from("/usr/bin/thunderbird"));
+        assert_eq!(e2.tags, e.tags);
+        assert!(!e2.anonymize);
+    }
+
+    #[test]
+    fn anonymized_event_json_round_trip() {
+        let clock = SimulatedClock::new(start_time(), false);
+        let mut e = window_event(&clock, "Bank account", "/usr/bin/firefox");
+        e.anonymize = true;
+
+        let val = e.to_json();
+        assert!(val["window_title"].is_null());
+        assert!(val["process_path"].is_null());
+        let e2 = ActiveWindowEvent::from_json(&val).unwrap();
+        assert!(e2.anonymize);
+        assert_eq!(e2.window_title, "");
+    }
+
+    #[test]
+    fn schema_v1_event_is_parsed() {
+        let val = json::parse(r#"{"type": "ActiveWindowEvent", "time": "2024-01-01T08:00:00+00:00", "duration": 15,
+            "hostname": "pc", "username": "user", "idle_for": 2, "process_path": "/usr/bin/xterm", "tags": ["work"]}"#).unwrap();
+        let e = ActiveWindowEvent::from_json(&val).unwrap();
+        assert_eq!(e.time, start_time());
+        assert_eq!(e.end, start_time());
+        assert_eq!(e.duration, Duration::from_secs(15));
+        assert_eq!(e.window_title, "");
+        assert_eq!(e.status, EventStatus::Active);
+        assert_eq!(e.process_path, PathBuf::from("/usr/bin/xterm"));
+        assert_eq!(e.tags.iter().collect::<Vec<_>>(), vec!["work"]);
+        assert!(!e.anonymize);
+    }
+
+    #[test]
+    fn invalid_durations_are_errors() {
+        for duration in ["-1", "1e300"] {
+            let val = json::parse(&format!(r#"{{"type": "ActiveWindowEvent", "schema_version": 2, "time": "2024-01-01T08:00:00+00:00",
+                "duration": {}, "hostname": "pc", "username": "user", "idle_for": 0, "window_title": "", "process_path": "/a", "tags": []}}"#,
+                duration)).unwrap();
+            assert!(ActiveWindowEvent::from_json(&val).is_err(), "duration {}", duration);
+        }
+    }
+
+    #[test]
+    fn newer_schema_version_is_rejected() {
+        let clock = SimulatedClock::new(start_time(), false);
+        let mut val = window_event(&clock, "a", "/a").to_json();
+        val["schema_version"] = (SCHEMA_VERSION + 1).into();
+        assert!(ActiveWindowEvent::from_json(&val).is_err());
+    }
+
+    /// Window sample `minutes` after `start_time`, sampled every minute.
+    fn window_sample(minutes: u64, title: &str, hostname: &str) -> ActiveWindowEvent {
+        let clock = SimulatedClock::new(start_time(), false);
+        clock.advance(Duration::from_secs(60 * minutes));
+        let mut e = ActiveWindowEvent::new(&clock, Duration::ZERO, title.to_string(), PathBuf::from("/usr/bin/code"), Duration::from_secs(60));
+        e.hostname = hostname.to_string();
+        e
+    }
+
+    fn sample(minutes: u64, title: &str, hostname: &str) -> Event {
+        Event::ActiveWindow(window_sample(minutes, title, hostname))
+    }
+
+    /// (title, hostname, start minute, end minute, duration in minutes) of each span.
+    fn spans(events: &[Event]) -> Vec<(&str, &str, i64, i64, u64)> {
+        events.iter()
+            .map(|e| match e {
+                Event::ActiveWindow(e) => (e.window_title.as_str(), e.hostname.as_str(), (e.time - start_time()).num_minutes(),
+                                           (e.end - start_time()).num_minutes(), e.duration.as_secs() / 60),
+                Event::Session(e) => (e.kind.type_name(), e.hostname.as_str(), (e.time - start_time()).num_minutes(), 0, 0),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn coalesce_merges_samples_up_to_max_gap() {
+        let events = vec![sample(1, "a", "pc"), sample(2, "a", "pc"), sample(3, "a", "pc"),
+                          sample(5, "a", "pc"), sample(8, "a", "pc"), sample(9, "b", "pc"), sample(10, "a", "pc")];
+        let max_gap = Duration::from_secs(120);
+
+        assert_eq!(spans(&coalesce(events.clone(), max_gap)), vec![
+            ("a", "pc", 1, 5, 4),
+            ("a", "pc", 8, 8, 1),
+            ("b", "pc", 9, 9, 1),
+            ("a", "pc", 10, 10, 1),
+        ]);
+        assert_eq!(coalesce(events, Duration::ZERO).len(), 7);
+    }
+
+    #[test]
+    fn coalesce_keeps_samples_apart_when_they_differ() {
+        let mut afk = window_sample(2, "a", "pc");
+        afk.status = EventStatus::Afk;
+        let mut tagged = window_sample(3, "a", "pc");
+        tagged.tags.push_back("work".to_string());
+        let mut anonymized = window_sample(4, "a", "pc");
+        anonymized.anonymize = true;
+        let events = vec![sample(1, "a", "pc"), Event::ActiveWindow(afk), Event::ActiveWindow(tagged), Event::ActiveWindow(anonymized)];
+        assert_eq!(coalesce(events, Duration::from_secs(120)).len(), 4);
+    }
+
+    #[test]
+    fn coalesce_merges_interleaved_hosts_separately() {
+        let events = vec![sample(1, "a", "pc"), sample(1, "a", "laptop"), sample(2, "a", "pc"), sample(2, "b", "laptop"),
+                          sample(3, "a", "pc"), sample(3, "b", "laptop"), sample(4, "b", "pc")];
+
+        assert_eq!(spans(&coalesce(events, Duration::from_secs(120))), vec![
+            ("a", "pc", 1, 3, 3),
+            ("a", "laptop", 1, 1, 1),
+            ("b", "laptop", 2, 3, 2),
+            ("b", "pc", 4, 4, 1),
+        ]);
+    }
+
+    #[test]
+    fn coalesce_merges_already_merged_spans() {
+        let max_gap = Duration::from_secs(120);
+        let first = coalesce(vec![sample(1, "a", "pc"), sample(2, "a", "pc")], max_gap);
+        let second = coalesce(vec![sample(3, "a", "pc"), sample(4, "a", "pc"), sample(5, "b", "pc")], max_gap);
+        let merged = coalesce(first.into_iter().chain(second), max_gap);
+        assert_eq!(spans(&merged), vec![("a", "pc", 1, 4, 4), ("b", "pc", 5, 5, 1)]);
+
+        // merging again changes nothing
+        assert_eq!(spans(&coalesce(merged.clone(), max_gap)), spans(&merged));
+
+        // spans out of order are not merged backwards
+        let backwards = coalesce(vec![sample(3, "a", "pc"), sample(1, "a", "pc")], max_gap);
+        assert_eq!(backwards.len(), 2);
+    }
+
+    #[test]
+    fn session_event_json_round_trip() {
+        let clock = SimulatedClock::new(start_time(), false);
+        let info = DaemonInfo { version: "0.1.0".to_string(), config_hash: "abc".to_string() };
+        let kinds = [
+            SessionEventKind::Lock,
+            SessionEventKind::Unlock,
+            SessionEventKind::AfkStart,
+            SessionEventKind::AfkEnd,
+            SessionEventKind::DaemonStart(info.clone()),
+            SessionEventKind::DaemonStop(info.clone()),
+            SessionEventKind::ConfigReload(info),
+            SessionEventKind::Suspend,
+            SessionEventKind::Resume,
+        ];
+
+        for kind in kinds {
+            let e = SessionEvent::new(&clock, kind.clone());
+            let val = json::parse(&Event::Session(e.clone()).to_json().dump()).unwrap();
+            assert_eq!(val["type"], kind.type_name());
+            assert_eq!(val["schema_version"], SCHEMA_VERSION);
+            let e2 = match Event::from_json(&val).unwrap() {
+                Event::Session(e2) => e2,
+                Event::ActiveWindow(e2) => panic!("{:?} read back as {:?}", kind, e2),
+            };
+            assert_eq!(e2.kind, kind);
+            assert_eq!(e2.time, e.time);
+            assert_eq!(e2.hostname, e.hostname);
+            assert_eq!(e2.username, e.username);
+        }
+    }
+
+    #[test]
+    fn invalid_session_events_are_errors() {
+        let parse = |s: &str| SessionEvent::from_json(&json::parse(s).unwrap());
+        assert!(parse(r#"{"type": "DaemonStart", "schema_version": 5, "time": "2024-01-01T08:00:00+00:00", "hostname": "pc", "username": "user"}"#).is_err());
+        assert!(parse(r#"{"type": "Reboot", "schema_version": 5, "time": "2024-01-01T08:00:00+00:00", "hostname": "pc", "username": "user"}"#).is_err());
+        assert!(parse(r#"{"type": "LockEvent", "schema_version": 5, "hostname": "pc", "username": "user"}"#).is_err());
+        assert!(parse(r#"{"type": "LockEvent", "schema_version": 5, "time": "2024-01-01T08:00:00+00:00", "hostname": "pc", "username": "user"}"#).is_ok());
+    }
+
+    #[test]
+    fn coalesce_splits_at_session_events() {
+        let session_event = |minutes: u64, hostname: &str, kind: SessionEventKind| {
+            let clock = SimulatedClock::new(start_time(), false);
+            clock.advance(Duration::from_secs(60 * minutes));
+            let mut e = SessionEvent::new(&clock, kind);
+            e.hostname = hostname.to_string();
+            Event::Session(e)
+        };
+        let events = vec![sample(1, "a", "pc"), sample(1, "a", "laptop"), sample(2, "a", "pc"), sample(2, "a", "laptop"),
+                          session_event(2, "laptop", SessionEventKind::Lock),
+                          sample(3, "a", "pc"), sample(3, "a", "laptop"),
+                          session_event(3, "pc", SessionEventKind::AfkStart),
+                          sample(4, "a", "pc"), sample(4, "a", "laptop")];
+
+        assert_eq!(spans(&coalesce(events, Duration::from_secs(120))), vec![
+            ("a", "pc", 1, 3, 3),
+            ("a", "laptop", 1, 2, 2),
+            ("LockEvent", "laptop", 2, 0, 0),
+            ("a", "laptop", 3, 4, 2),
+            ("AfkStart", "pc", 3, 0, 0),
+            ("a", "pc", 4, 4, 1),
+        ]);
+    }
+}
@@ -0,0 +1,136 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+use age::x25519::Recipient;
+use anyhow::{anyhow, bail, Result};
+use json::JsonValue;
+use crate::watcher::compression;
+
+const AGE_MAGIC: &[u8] = b"age-encryption.org/";
+
+/// File name extension appended to encrypted files.
+pub const ENCRYPTED_EXTENSION: &str = "age";
+
+/// Private keys for reading encrypted event logs.
+pub type Identities = Vec<Box<dyn age::Identity>>;
+
+/// Parse `age` X25519 public keys (`"age1..."`) from a string, array of strings, or null.
+pub fn recipients_from_json(val: &JsonValue) -> Result<Vec<Recipient>> {
+    let parse = |v: &JsonValue| -> Result<Recipient> {
+        let s = v.as_str().ok_or(anyhow!("encryption recipient must be a string, not {:?}", v))?;
+        Recipient::from_str(s.trim()).map_err(|e| anyhow!("invalid encryption recipient {:?}: {}", s, e))
+    };
+
+    if val.is_null() {
+        Ok(vec![])
+    } else if val.is_string() {
+        Ok(vec![parse(val)?])
+    } else if val.is_array() {
+        val.members().map(parse).collect()
+    } else {
+        bail!("encryption recipients must be string, array of strings, or null")
+    }
+}
+
+/// Encrypt data to all recipients in the `age` format.
+pub fn encrypt(data: &[u8], recipients: &[Recipient]) -> Result<Vec<u8>> {
+    let encryptor = age::Encryptor::with_recipients(recipients.iter().map(|r| r as &dyn age::Recipient))?;
+    let mut output = Vec::new();
+    let mut writer = encryptor.wrap_output(&mut output)?;
+    writer.write_all(data)?;
+    writer.finish()?;
+    Ok(output)
+}
+
+/// Read private keys (`AGE-SECRET-KEY-...`) from an `age` identity file.
+pub fn read_identities(path: &Path) -> Result<Identities> {
+    let data = fs::read_to_string(path)?;
+    let identity_file = age::IdentityFile::from_buffer(data.as_bytes())?;
+    Ok(identity_file.into_identities()?)
+}
+
+pub fn is_encrypted(header: &[u8]) -> bool {
+    header.starts_with(AGE_MAGIC)
+}
+
+/// Open file for reading, transparently decrypting (with given identities) and decompressing it if needed.
+pub fn open_reader(path: &Path, identities: &[Box<dyn age::Identity>]) -> Result<Box<dyn BufRead>> {
+    let mut reader = BufReader::new(fs::File::open(path)?);
+
+    if !is_encrypted(reader.fill_buf()?) {
+        return compression::decompress_reader(reader);
+    }
+
+    if identities.is_empty() {
+        bail!("{:?} is encrypted, a private key is needed to read it", path);
+    }
+
+    let decryptor = age::Decryptor::new_buffered(reader)?;
+    let decrypted = decryptor.decrypt(identities.iter().map(|i| i.as_ref() as &dyn age::Identity))
+        .map_err(|e| anyhow!("cannot decrypt {:?}: {}", path, e))?;
+    compression::decompress_reader(BufReader::new(decrypted))
+}
+
+/// Read whole file as string, transparently decrypting and decompressing it if needed.
+pub fn read_to_string(path: &Path, identities: &[Box<dyn age::Identity>]) -> Result<String> {
+    let mut data = String::new();
+    open_reader(path, identities)?.read_to_string(&mut data)?;
+    Ok(data)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use age::secrecy::ExposeSecret;
+    use crate::test_util::TestDir;
+    use crate::watcher::compression::Compression;
+
+    const DATA: &[u8] = b"{\"a\":1}\n{\"b\":2}\n";
+
+    #[test]
+    fn encrypted_data_round_trips() {
+        let key = age::x25519::Identity::generate();
+        let identities: Identities = vec![Box::new(key.clone())];
+        let dir = TestDir::new("encryption-round-trip");
+
+        for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+            let encrypted = encrypt(&compression.compress(DATA).unwrap(), &[key.to_public()]).unwrap();
+            assert!(is_encrypted(&encrypted));
+
+            let path = dir.write(compression.extension(), &encrypted);
+            let data = read_to_string(&path, &identities);
+            assert_eq!(data.unwrap().as_bytes(), DATA, "{:?}", compression);
+        }
+    }
+
+    #[test]
+    fn wrong_or_missing_identity_is_an_error() {
+        let key = age::x25519::Identity::generate();
+        let other: Identities = vec![Box::new(age::x25519::Identity::generate())];
+        let dir = TestDir::new("encryption-wrong-identity");
+        let path = dir.write("wrong-identity.jsonl.age", encrypt(DATA, &[key.to_public()]).unwrap());
+
+        let wrong = read_to_string(&path, &other);
+        let missing = read_to_string(&path, &[]);
+        assert!(wrong.unwrap_err().to_string().contains("cannot decrypt"));
+        assert!(missing.unwrap_err().to_string().contains("private key is needed"));
+    }
+
+    #[test]
+    fn identities_and_recipients_are_parsed() {
+        let key = age::x25519::Identity::generate();
+        let dir = TestDir::new("encryption-identities");
+        let path = dir.write("identity.txt", format!("# key\n{}\n", key.to_string().expose_secret()));
+        let identities = read_identities(&path);
+        assert_eq!(identities.unwrap().len(), 1);
+
+        let public = key.to_public().to_string();
+        assert_eq!(recipients_from_json(&json::parse(&format!("{:?}", public)).unwrap()).unwrap().len(), 1);
+        assert_eq!(recipients_from_json(&json::array![public.clone(), public]).unwrap().len(), 2);
+        assert!(recipients_from_json(&JsonValue::Null).unwrap().is_empty());
+        assert!(recipients_from_json(&json::parse("\"age1nope\"").unwrap()).is_err());
+        assert!(recipients_from_json(&json::parse("1").unwrap()).is_err());
+    }
+}
@@ -0,0 +1,221 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Result};
+use crate::watcher::core::Event;
+
+/// Local write-ahead journal of events that were sampled but not yet written to the output dir.
+///
+/// Each event is appended and fsynced as soon as it is recorded. Events left in the journal
+/// (eg. after power loss) are replayed on the next start. Once events are safely written
+/// to the output dir, they are discarded from the start of the journal.
+pub struct Journal {
+    path: PathBuf,
+    file: fs::File,
+    /// Number of events in the journal file, one per line.
+    len: usize,
+}
+
+impl Journal {
+    /// Open journal, creating it if needed, and read events left over from a previous run.
+    pub fn open(path: &Path) -> Result<(Journal, Vec<Event>)> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let (events, lines, skipped) = if path.exists() {
+            Journal::read_events(path)?
+        } else {
+            (vec![], vec![], 0)
+        };
+
+        let file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+        let mut journal = Journal { path: path.to_path_buf(), file, len: events.len() };
+        if skipped > 0 {
+            // keep one line per event, so that `discard` knows which lines to drop
+            journal.rewrite(&lines)?;
+        }
+        Ok((journal, events))
+    }
+
+    /// Read events and the lines they were read from, and the number of unreadable lines.
+    fn read_events(path: &Path) -> Result<(Vec<Event>, Vec<String>, usize)> {
+        let reader = BufReader::new(fs::File::open(path)?);
+        let mut events = Vec::new();
+        let mut lines = Vec::new();
+        let mut skipped = 0;
+
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                skipped += 1;
+                continue;
+            }
+
+            // the last line may be cut short by a crash
+            match json::parse(line.as_str()).map_err(anyhow::Error::from).and_then(|val| Event::from_json(&val)) {
+                Ok(e) => {
+                    events.push(e);
+                    lines.push(line);
+                }
+                Err(e) => {
+                    println!("Skipping unreadable journal entry {:?}, line {}: {:?}", path, i + 1, e);
+                    skipped += 1;
+                }
+            }
+        }
+
+        Ok((events, lines, skipped))
+    }
+
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    /// Number of events in the journal.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn append(&mut self, e: &Event) -> Result<()> {
+        let mut line = e.to_json().dump().into_bytes();
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.sync_data()?;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Read the last `count` events, in the order they were appended.
+    pub fn read_last(&self, count: usize) -> Result<Vec<Event>> {
+        let (events, _, _) = Journal::read_events(&self.path)?;
+        if events.len() != self.len {
+            bail!("journal {:?} has {} readable events, expected {}", self.path, events.len(), self.len);
+        }
+        Ok(events.into_iter().skip(self.len.saturating_sub(count)).collect())
+    }
+
+    /// Discard the first `count` events (in the order they were appended), to be called
+    /// once they are written to the output dir.
+    pub fn discard(&mut self, count: usize) -> Result<()> {
+        if count >= self.len {
+            self.file.set_len(0)?;
+            self.file.sync_all()?;
+            self.len = 0;
+            return Ok(());
+        }
+
+        let lines = BufReader::new(fs::File::open(&self.path)?).lines()
+            .skip(count)
+            .collect::<std::io::Result<Vec<String>>>()?;
+        self.rewrite(&lines)
+    }
+
+    /// Replace content of the journal by `lines`, atomically.
+    fn rewrite(&mut self, lines: &[String]) -> Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        let mut tmp_file = fs::File::create(&tmp_path)?;
+        for line in lines {
+            tmp_file.write_all(line.as_bytes())?;
+            tmp_file.write_all(b"\n")?;
+        }
+        tmp_file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+
+        self.file = fs::OpenOptions::new().append(true).open(&self.path)?;
+        self.len = lines.len();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{DateTime, Utc};
+    use crate::test_util::{start_time, TestDir};
+    use crate::watcher::clock::SimulatedClock;
+    use crate::watcher::core::{SessionEvent, SessionEventKind};
+
+    fn events(count: usize) -> Vec<Event> {
+        let clock = SimulatedClock::new(start_time(), false);
+        (0..count)
+            .map(|_| {
+                clock.advance(std::time::Duration::from_secs(60));
+                Event::Session(SessionEvent::new(&clock, SessionEventKind::Lock))
+            })
+            .collect()
+    }
+
+    fn times(events: &[Event]) -> Vec<DateTime<Utc>> {
+        events.iter().map(|e| e.time()).collect()
+    }
+
+    #[test]
+    fn replays_appended_events() {
+        let dir = TestDir::new("journal-replay");
+        let path = dir.join("journal.jsonl");
+        let events = events(3);
+        {
+            let (mut journal, replayed) = Journal::open(&path).unwrap();
+            assert!(replayed.is_empty());
+            for e in &events {
+                journal.append(e).unwrap();
+            }
+            assert_eq!(journal.len(), 3);
+        }
+
+        let (journal, replayed) = Journal::open(&path).unwrap();
+        assert_eq!(journal.len(), 3);
+        assert_eq!(times(&replayed), times(&events));
+    }
+
+    #[test]
+    fn discard_keeps_later_events() {
+        let dir = TestDir::new("journal-discard");
+        let path = dir.join("journal.jsonl");
+        let events = events(5);
+        let (mut journal, _) = Journal::open(&path).unwrap();
+        for e in &events[..3] {
+            journal.append(e).unwrap();
+        }
+
+        journal.discard(2).unwrap();
+        for e in &events[3..] {
+            journal.append(e).unwrap();
+        }
+        assert_eq!(journal.len(), 3);
+        drop(journal);
+
+        let (mut journal, replayed) = Journal::open(&path).unwrap();
+        assert_eq!(times(&replayed), times(&events[2..]));
+        journal.discard(3).unwrap();
+        assert!(journal.is_empty());
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn skips_line_cut_short_by_crash() {
+        let dir = TestDir::new("journal-crash");
+        let path = dir.join("journal.jsonl");
+        let events = events(2);
+        let (mut journal, _) = Journal::open(&path).unwrap();
+        journal.append(&events[0]).unwrap();
+        drop(journal);
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"type\": \"LockEv").unwrap();
+        drop(file);
+
+        let (mut journal, replayed) = Journal::open(&path).unwrap();
+        assert_eq!(times(&replayed), times(&events[..1]));
+        journal.append(&events[1]).unwrap();
+        journal.discard(1).unwrap();
+        drop(journal);
+
+        let (_, replayed) = Journal::open(&path).unwrap();
+        assert_eq!(times(&replayed), times(&events[1..]));
+    }
+}
@@ -1,17 +1,63 @@
 pub mod core;
 pub mod platforms;
 pub mod config;
+pub mod clock;
+pub mod writer;
+pub mod journal;
+pub mod compression;
+pub mod encryption;
+pub mod anonymize;
+pub mod config_watcher;
+use std::sync::Arc;
 use anyhow::Result;
+use crate::watcher::clock::Clock;
 use crate::watcher::config::Config;
 use crate::watcher::core::Desktop;
 
-pub fn get_desktop(config: &Config) -> Result<Box<dyn core::Desktop>> {
+pub fn get_desktop(config: &Config, clock: Arc<dyn Clock>) -> Result<Box<dyn core::Desktop>> {
+    if let Some(timeline_path) = &config.scripted_desktop {
+        let desktop = Box::new(platforms::scripted::ScriptedDesktop::from_file(timeline_path, clock)?);
+        desktop.check_implementation_available()?;
+        return Ok(desktop);
+    }
+
     #[cfg(unix)]
     fn get_desktop_impl(_config: &Config) -> Result<Box<dyn core::Desktop>> {
         // TODO support more UNIX platforms, possibly use config to request a particular impl.
-        
+
+        let desktop = get_window_system_desktop()?;
+
+        let session = platforms::logind::LogindSession::connect_system()
+            .and_then(|session| session.check_available().map(|_| session));
+        match session {
+            Ok(session) => {
+                println!("Using systemd-logind session {} for screen lock and idle state", session.session_path());
+                Ok(Box::new(platforms::logind::LogindDesktop::new(desktop, session)))
+            }
+            Err(e) => {
+                println!("Warning: systemd-logind not available, using {} alone for screen lock and idle state: {:?}",
+                         desktop.implementation_name(), e);
+                Ok(desktop)
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    fn get_window_system_desktop() -> Result<Box<dyn core::Desktop>> {
+        // sway/i3 export their IPC socket into the session environment
+        if let Some(sway_desktop) = platforms::sway::SwayDesktop::from_env() {
+            sway_desktop.check_implementation_available()?;
+            return Ok(Box::new(sway_desktop));
+        }
+
+        let x11_desktop = platforms::x11::X11Desktop::connect()
+            .and_then(|d| d.check_implementation_available().map(|_| d));
+        match x11_desktop {
+            Ok(x11_desktop) => return Ok(Box::new(x11_desktop)),
+            Err(e) => println!("X11Desktop not available, falling back to GnomeDesktop: {:?}", e)
+        }
+
         let desktop = Box::new(platforms::linux::GnomeDesktop);
-        
         desktop.check_implementation_available()?;
         Ok(desktop)
     }
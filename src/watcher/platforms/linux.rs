@@ -16,7 +16,8 @@ impl Desktop for GnomeDesktop {
     }
 
     fn check_implementation_available(&self) -> Result<()> {
-        let commands_to_test = ["gnome-screensaver-command", "xprintidle", "xdotool"];
+        // gnome-screensaver-command is optional, lock state is also available from systemd-logind
+        let commands_to_test = ["xprintidle", "xdotool"];
 
         for cmd in commands_to_test {
             let output = Command::new(cmd)
@@ -43,8 +44,7 @@ impl Desktop for GnomeDesktop {
         match output {
             Ok(output_) => {
                 let s = String::from_utf8(output_.stdout).unwrap();
-                let locked = s.contains("is active");
-                locked
+                s.contains("is active")
             }
             Err(_) => false
         }
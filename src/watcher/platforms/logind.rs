@@ -0,0 +1,402 @@
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::watcher::core::{Window, Desktop};
+use anyhow::Result;
+use zbus::blocking::{Connection, Proxy};
+use zbus::zvariant::OwnedObjectPath;
+
+// https://www.freedesktop.org/software/systemd/man/latest/org.freedesktop.login1.html
+const LOGIND_DESTINATION: &str = "org.freedesktop.login1";
+const LOGIND_MANAGER_PATH: &str = "/org/freedesktop/login1";
+const LOGIND_MANAGER_INTERFACE: &str = "org.freedesktop.login1.Manager";
+const LOGIND_SESSION_INTERFACE: &str = "org.freedesktop.login1.Session";
+const LOGIND_AUTO_SESSION_PATH: &str = "/org/freedesktop/login1/session/auto";
+
+/// Screen lock and idle state of the current systemd-logind session, read over D-Bus.
+///
+/// Lock state is taken from the `LockedHint` property (set by screen lockers) and from
+/// the `Lock`/`Unlock` signals (sent by `loginctl lock-session` and friends). A lock
+/// request is considered pending until `LockedHint` confirms it, `Unlock` arrives or the
+/// user is active again. The latter matters for screen lockers that set no `LockedHint`
+/// (eg. i3lock started by xss-lock), since unlocking them sends no `Unlock` either.
+///
+/// Signals are received by a background thread, which ends when the session is dropped;
+/// this also closes the bus connection.
+pub struct LogindSession {
+    proxy: Proxy<'static>,
+    /// When a `Lock` signal arrived that was not confirmed or cancelled yet.
+    lock_requested_at: Arc<Mutex<Option<Instant>>>,
+    signal_thread: Option<JoinHandle<()>>,
+}
+
+impl LogindSession {
+    /// Connect to the system bus.
+    pub fn connect_system() -> Result<LogindSession> {
+        LogindSession::connect(Connection::system()?)
+    }
+
+    /// Connect using given bus connection, eg. to a private `dbus-daemon`.
+    ///
+    /// The session is `$XDG_SESSION_ID` if set, otherwise the one logind resolves
+    /// as `auto` for this process (the user's graphical session when running as
+    /// a systemd user service).
+    pub fn connect(conn: Connection) -> Result<LogindSession> {
+        let session_id = match env::var("XDG_SESSION_ID") {
+            Ok(session_id) if !session_id.is_empty() => session_id,
+            _ => {
+                let auto_proxy = Proxy::new(&conn, LOGIND_DESTINATION, LOGIND_AUTO_SESSION_PATH, LOGIND_SESSION_INTERFACE)?;
+                auto_proxy.get_property::<String>("Id")?
+            }
+        };
+
+        // signals are only emitted on the real session path, not the "auto" alias
+        let manager_proxy = Proxy::new(&conn, LOGIND_DESTINATION, LOGIND_MANAGER_PATH, LOGIND_MANAGER_INTERFACE)?;
+        let session_path: OwnedObjectPath = manager_proxy.call("GetSession", &(session_id.as_str(),))?;
+        let proxy = Proxy::new_owned(conn, LOGIND_DESTINATION, session_path, LOGIND_SESSION_INTERFACE)?;
+
+        let lock_requested_at = Arc::new(Mutex::new(None));
+        let signals = proxy.receive_all_signals()?;
+        let lock_requested_at_ = lock_requested_at.clone();
+        let signal_thread = thread::spawn(move || {
+            // ends when the connection is closed, see `drop()`
+            for msg in signals {
+                let header = msg.header();
+                match header.member().map(|m| m.as_str()) {
+                    Some("Lock") => {
+                        println!("Received logind Lock signal");
+                        *lock_requested_at_.lock().unwrap() = Some(Instant::now());
+                    }
+                    Some("Unlock") => {
+                        println!("Received logind Unlock signal");
+                        *lock_requested_at_.lock().unwrap() = None;
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        Ok(LogindSession { proxy, lock_requested_at, signal_thread: Some(signal_thread) })
+    }
+
+    pub fn session_path(&self) -> String {
+        self.proxy.path().to_string()
+    }
+
+    /// Check that lock and idle state can be read.
+    pub fn check_available(&self) -> Result<()> {
+        self.proxy.get_property::<bool>("LockedHint")?;
+        self.proxy.get_property::<bool>("IdleHint")?;
+        Ok(())
+    }
+
+    /// Whether the session is locked; `idle_for` is the time since the last user input,
+    /// a pending lock request is dropped if there was input after it.
+    pub fn is_locked(&self, idle_for: Duration) -> Result<bool> {
+        let locked_hint: bool = self.proxy.get_property("LockedHint")?;
+        let mut lock_requested_at = self.lock_requested_at.lock().unwrap();
+        match *lock_requested_at {
+            Some(_) if locked_hint => {
+                // the screen locker took over, it will clear LockedHint on unlock
+                *lock_requested_at = None;
+            }
+            Some(time) if idle_for < time.elapsed() => {
+                println!("User is active after logind Lock signal, assuming the screen was unlocked");
+                *lock_requested_at = None;
+            }
+            _ => {}
+        }
+        Ok(locked_hint || lock_requested_at.is_some())
+    }
+
+    /// Time since the session became idle according to `IdleHint`, zero if not idle.
+    pub fn get_idle_duration(&self) -> Result<Duration> {
+        let idle_hint: bool = self.proxy.get_property("IdleHint")?;
+        if !idle_hint {
+            return Ok(Duration::from_millis(0));
+        }
+
+        let idle_since_usec: u64 = self.proxy.get_property("IdleSinceHint")?;
+        let idle_since = UNIX_EPOCH + Duration::from_micros(idle_since_usec);
+        Ok(SystemTime::now().duration_since(idle_since).unwrap_or_default())
+    }
+}
+
+impl Drop for LogindSession {
+    fn drop(&mut self) {
+        // closing the connection ends the signal iterator of the signal thread
+        if let Err(e) = self.proxy.connection().clone().close() {
+            println!("Failed to close logind connection: {:?}", e);
+        }
+        if let Some(signal_thread) = self.signal_thread.take() {
+            let _ = signal_thread.join();
+        }
+    }
+}
+
+/// Wraps another `Desktop`, taking screen lock and idle state from systemd-logind
+/// in addition to the wrapped implementation.
+pub struct LogindDesktop {
+    inner: Box<dyn Desktop>,
+    session: LogindSession,
+}
+
+impl LogindDesktop {
+    pub fn new(inner: Box<dyn Desktop>, session: LogindSession) -> LogindDesktop {
+        LogindDesktop { inner, session }
+    }
+}
+
+impl Desktop for LogindDesktop {
+    fn implementation_name(&self) -> &'static str {
+        self.inner.implementation_name()
+    }
+
+    fn check_implementation_available(&self) -> Result<()> {
+        self.session.check_available()?;
+        self.inner.check_implementation_available()
+    }
+
+    fn is_screen_locked(&self) -> bool {
+        match self.session.is_locked(self.get_idle_duration()) {
+            Ok(true) => true,
+            Ok(false) => self.inner.is_screen_locked(),
+            Err(e) => {
+                println!("Failed to read logind LockedHint: {:?}", e);
+                self.inner.is_screen_locked()
+            }
+        }
+    }
+
+    fn get_idle_duration(&self) -> Duration {
+        let inner_idle = self.inner.get_idle_duration();
+        match self.session.get_idle_duration() {
+            Ok(logind_idle) => inner_idle.max(logind_idle),
+            Err(e) => {
+                println!("Failed to read logind IdleHint: {:?}", e);
+                inner_idle
+            }
+        }
+    }
+
+    fn get_active_window(&self) -> Result<Box<dyn Window>> {
+        self.inner.get_active_window()
+    }
+
+    fn before_main_loop_start(&self) -> Result<()> {
+        self.inner.before_main_loop_start()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use zbus::object_server::SignalEmitter;
+    use crate::test_util::TestDir;
+
+    const SESSION_PATH: &str = "/org/freedesktop/login1/session/_31";
+
+    struct FakeManager;
+
+    #[zbus::interface(name = "org.freedesktop.login1.Manager")]
+    impl FakeManager {
+        fn get_session(&self, _id: &str) -> OwnedObjectPath {
+            OwnedObjectPath::try_from(SESSION_PATH).unwrap()
+        }
+    }
+
+    #[derive(Default)]
+    struct FakeSession {
+        locked_hint: bool,
+        idle_hint: bool,
+        idle_since_hint: u64,
+    }
+
+    #[zbus::interface(name = "org.freedesktop.login1.Session")]
+    impl FakeSession {
+        #[zbus(property)]
+        fn id(&self) -> String {
+            "1".to_string()
+        }
+
+        #[zbus(property)]
+        fn locked_hint(&self) -> bool {
+            self.locked_hint
+        }
+
+        #[zbus(property)]
+        fn idle_hint(&self) -> bool {
+            self.idle_hint
+        }
+
+        #[zbus(property)]
+        fn idle_since_hint(&self) -> u64 {
+            self.idle_since_hint
+        }
+
+        #[zbus(signal)]
+        async fn lock(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+
+        #[zbus(signal)]
+        async fn unlock(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+    }
+
+    /// Private `dbus-daemon` with a fake logind on it, stopped when dropped.
+    struct FakeLogind {
+        /// Holds the bus socket, removed after the daemon is stopped.
+        _dir: TestDir,
+        daemon: Child,
+        address: String,
+        server: Connection,
+    }
+
+    impl FakeLogind {
+        fn start(name: &str) -> FakeLogind {
+            let dir = TestDir::new(name);
+            let config = dir.write("bus.conf", format!(r#"<busconfig>
+                <type>session</type>
+                <listen>unix:path={}</listen>
+                <policy context="default"><allow send_destination="*"/><allow receive_sender="*"/><allow own="*"/></policy>
+            </busconfig>"#, dir.join("bus").display()));
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--nofork", "--print-address", "--config-file"]).arg(&config)
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .expect("cannot start dbus-daemon");
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap()).read_line(&mut address).unwrap();
+            let address = address.trim().to_string();
+
+            let server = zbus::blocking::connection::Builder::address(address.as_str()).unwrap()
+                .serve_at(LOGIND_MANAGER_PATH, FakeManager).unwrap()
+                .serve_at(LOGIND_AUTO_SESSION_PATH, FakeSession::default()).unwrap()
+                .serve_at(SESSION_PATH, FakeSession::default()).unwrap()
+                .name(LOGIND_DESTINATION).unwrap()
+                .build().unwrap();
+            FakeLogind { _dir: dir, daemon, address, server }
+        }
+
+        fn connect(&self) -> LogindSession {
+            let conn = zbus::blocking::connection::Builder::address(self.address.as_str()).unwrap().build().unwrap();
+            LogindSession::connect(conn).unwrap()
+        }
+
+        /// Change session properties and announce the change, as logind does.
+        fn update(&self, f: impl FnOnce(&mut FakeSession)) {
+            let iface = self.server.object_server().interface::<_, FakeSession>(SESSION_PATH).unwrap();
+            f(&mut iface.get_mut());
+            zbus::block_on(async {
+                let session = iface.get();
+                session.locked_hint_changed(iface.signal_emitter()).await.unwrap();
+                session.idle_hint_changed(iface.signal_emitter()).await.unwrap();
+                session.idle_since_hint_changed(iface.signal_emitter()).await.unwrap();
+            });
+        }
+
+        fn emit_lock(&self, lock: bool) {
+            let iface = self.server.object_server().interface::<_, FakeSession>(SESSION_PATH).unwrap();
+            zbus::block_on(async {
+                match lock {
+                    true => FakeSession::lock(iface.signal_emitter()).await.unwrap(),
+                    false => FakeSession::unlock(iface.signal_emitter()).await.unwrap(),
+                }
+            });
+        }
+    }
+
+    impl Drop for FakeLogind {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    /// Wait for signals and property changes to reach the session.
+    fn wait_until(what: &str, mut f: impl FnMut() -> bool) {
+        for _ in 0..100 {
+            if f() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        panic!("timed out waiting until {}", what);
+    }
+
+    const LONG_IDLE: Duration = Duration::from_secs(3600);
+
+    #[test]
+    #[ignore = "needs dbus-daemon, run with --ignored"]
+    fn reads_locked_and_idle_hints() {
+        let logind = FakeLogind::start("logind-hints");
+        let session = logind.connect();
+        assert_eq!(session.session_path(), SESSION_PATH);
+        session.check_available().unwrap();
+        assert!(!session.is_locked(LONG_IDLE).unwrap());
+        assert_eq!(session.get_idle_duration().unwrap(), Duration::ZERO);
+
+        logind.update(|s| s.locked_hint = true);
+        wait_until("LockedHint is set", || session.is_locked(Duration::ZERO).unwrap());
+
+        let idle_since = SystemTime::now() - Duration::from_secs(60);
+        logind.update(|s| {
+            s.locked_hint = false;
+            s.idle_hint = true;
+            s.idle_since_hint = idle_since.duration_since(UNIX_EPOCH).unwrap().as_micros() as u64;
+        });
+        wait_until("LockedHint is cleared", || !session.is_locked(LONG_IDLE).unwrap());
+        // IdleSinceHint may arrive after IdleHint
+        let idle_range = Duration::from_secs(60)..Duration::from_secs(70);
+        wait_until("IdleSinceHint is set", || idle_range.contains(&session.get_idle_duration().unwrap()));
+    }
+
+    #[test]
+    #[ignore = "needs dbus-daemon, run with --ignored"]
+    fn lock_signal_is_pending_until_confirmed_or_cancelled() {
+        let logind = FakeLogind::start("logind-signals");
+        let session = logind.connect();
+
+        // cancelled by Unlock
+        logind.emit_lock(true);
+        wait_until("Lock is received", || session.is_locked(LONG_IDLE).unwrap());
+        logind.emit_lock(false);
+        wait_until("Unlock is received", || !session.is_locked(LONG_IDLE).unwrap());
+
+        // cancelled by user activity after the signal
+        logind.emit_lock(true);
+        wait_until("Lock is received", || session.is_locked(LONG_IDLE).unwrap());
+        thread::sleep(Duration::from_millis(10));
+        assert!(!session.is_locked(Duration::ZERO).unwrap());
+        assert!(!session.is_locked(LONG_IDLE).unwrap());
+
+        // confirmed by LockedHint, which then decides alone
+        logind.emit_lock(true);
+        wait_until("Lock is received", || session.is_locked(LONG_IDLE).unwrap());
+        logind.update(|s| s.locked_hint = true);
+        wait_until("LockedHint confirms the lock", || {
+            session.is_locked(LONG_IDLE).unwrap() && session.lock_requested_at.lock().unwrap().is_none()
+        });
+        assert!(session.is_locked(Duration::ZERO).unwrap());
+        logind.update(|s| s.locked_hint = false);
+        wait_until("LockedHint is cleared", || !session.is_locked(LONG_IDLE).unwrap());
+    }
+
+    #[test]
+    #[ignore = "needs dbus-daemon, run with --ignored"]
+    fn drop_closes_connection_and_joins_signal_thread() {
+        let logind = FakeLogind::start("logind-drop");
+        let session = logind.connect();
+        let conn = session.proxy.connection().clone();
+
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        thread::spawn(move || {
+            drop(session);
+            sender.send(()).unwrap();
+        });
+        receiver.recv_timeout(Duration::from_secs(5)).expect("dropping LogindSession hangs");
+        assert!(conn.call_method(Some("org.freedesktop.DBus"), "/org/freedesktop/DBus", Some("org.freedesktop.DBus"), "GetId", &()).is_err());
+    }
+}
@@ -1,6 +1,17 @@
+pub mod scripted;
+
 #[cfg(target_os = "linux")]
 pub mod linux;
 
+#[cfg(target_os = "linux")]
+pub mod logind;
+
+#[cfg(target_os = "linux")]
+pub mod sway;
+
+#[cfg(target_os = "linux")]
+pub mod x11;
+
 #[cfg(target_os = "windows")]
 pub mod windows;
 
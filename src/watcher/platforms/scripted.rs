@@ -0,0 +1,215 @@
+use std::cell::Cell;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::watcher::clock::Clock;
+use crate::watcher::config::check_keys;
+use crate::watcher::core::{Window, Desktop};
+use anyhow::{anyhow, bail, Context, Result};
+use json::JsonValue;
+
+/// Fake desktop that plays back a timeline from a JSON file, for tests and demos.
+///
+/// The timeline file looks like this:
+///
+/// ```json
+/// {
+///   "loop": false,
+///   "steps": [
+///     {"duration_sec": 60, "window_title": "Inbox", "process_path": "/usr/bin/thunderbird", "process_id": 42},
+///     {"duration_sec": 30, "window_title": "Inbox", "process_path": "/usr/bin/thunderbird", "idle_sec": 10},
+///     {"duration_sec": 600, "locked": true}
+///   ]
+/// }
+/// ```
+///
+/// Each step lasts `duration_sec`. During a step with `idle_sec`, the user is idle for
+/// `idle_sec` at the start of the step plus the time elapsed within the step. A step
+/// without `window_title` has no active window. Unless `loop` is true, the desktop
+/// reports `is_finished()` after the last step.
+pub struct ScriptedDesktop {
+    steps: Vec<ScriptedStep>,
+    loop_: bool,
+    clock: Arc<dyn Clock>,
+    start: Cell<Instant>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ScriptedStep {
+    pub duration: Duration,
+    pub locked: bool,
+    pub idle_for: Option<Duration>,
+    pub window_title: Option<String>,
+    pub process_path: Option<PathBuf>,
+    pub process_id: Option<u64>,
+}
+
+pub struct ScriptedWindow { step: ScriptedStep }
+
+const TIMELINE_KEYS: [&str; 2] = ["loop", "steps"];
+const STEP_KEYS: [&str; 6] = ["duration_sec", "locked", "idle_sec", "window_title", "process_path", "process_id"];
+
+impl ScriptedStep {
+    pub fn from_json(val: &JsonValue) -> Result<ScriptedStep> {
+        if !val.is_object() {
+            bail!("ScriptedDesktop step should be an object, not {:?}", val);
+        }
+
+        check_keys(val, "", &STEP_KEYS)?;
+
+        let read_duration = |key: &str| -> Result<Option<Duration>> {
+            if val[key].is_null() {
+                return Ok(None);
+            }
+            let secs = val[key].as_f64().ok_or(anyhow!("cannot read {}", key))?;
+            Duration::try_from_secs_f64(secs).map(Some).map_err(|e| anyhow!("invalid {} {}: {}", key, secs, e))
+        };
+        let duration = read_duration("duration_sec")?.ok_or(anyhow!("cannot read duration_sec"))?;
+        let locked = match &val["locked"] {
+            JsonValue::Null => false,
+            JsonValue::Boolean(tmp) => *tmp,
+            other => bail!("locked should be a boolean, not {}", other),
+        };
+        let idle_for = read_duration("idle_sec")?;
+        let window_title = val["window_title"].as_str().map(String::from);
+        let process_path = val["process_path"].as_str().map(PathBuf::from);
+        let process_id = val["process_id"].as_u64();
+
+        Ok(ScriptedStep { duration, locked, idle_for, window_title, process_path, process_id })
+    }
+}
+
+impl ScriptedDesktop {
+    pub fn new(steps: Vec<ScriptedStep>, loop_: bool, clock: Arc<dyn Clock>) -> ScriptedDesktop {
+        let start = Cell::new(clock.instant());
+        ScriptedDesktop { steps, loop_, clock, start }
+    }
+
+    pub fn from_json(val: &JsonValue, clock: Arc<dyn Clock>) -> Result<ScriptedDesktop> {
+        check_keys(val, "", &TIMELINE_KEYS)?;
+
+        let mut steps = Vec::new();
+        for (i, v) in val["steps"].members().enumerate() {
+            steps.push(ScriptedStep::from_json(v).with_context(|| format!("invalid ScriptedDesktop step {}", i))?);
+        }
+        let loop_ = match &val["loop"] {
+            JsonValue::Null => false,
+            JsonValue::Boolean(tmp) => *tmp,
+            other => bail!("loop should be a boolean, not {}", other),
+        };
+
+        Ok(ScriptedDesktop::new(steps, loop_, clock))
+    }
+
+    pub fn from_file(path: &Path, clock: Arc<dyn Clock>) -> Result<ScriptedDesktop> {
+        let data = fs::read_to_string(path)?;
+        let d = json::parse(data.as_str())?;
+        ScriptedDesktop::from_json(&d, clock)
+    }
+
+    fn total_duration(&self) -> Duration {
+        self.steps.iter().map(|s| s.duration).sum()
+    }
+
+    /// Current step and time elapsed within it, or None after the end of timeline.
+    fn current_step(&self) -> Option<(&ScriptedStep, Duration)> {
+        let total_duration = self.total_duration();
+        let mut elapsed = self.clock.instant() - self.start.get();
+
+        if self.loop_ && !total_duration.is_zero() {
+            elapsed = Duration::from_secs_f64(elapsed.as_secs_f64() % total_duration.as_secs_f64());
+        }
+
+        for step in &self.steps {
+            if elapsed < step.duration {
+                return Some((step, elapsed));
+            }
+            elapsed -= step.duration;
+        }
+
+        None
+    }
+}
+
+impl Desktop for ScriptedDesktop {
+    fn implementation_name(&self) -> &'static str {
+        "ScriptedDesktop"
+    }
+
+    fn check_implementation_available(&self) -> Result<()> {
+        if self.steps.is_empty() {
+            bail!("ScriptedDesktop timeline has no steps");
+        }
+        Ok(())
+    }
+
+    fn is_screen_locked(&self) -> bool {
+        match self.current_step() {
+            Some((step, _)) => step.locked,
+            None => false
+        }
+    }
+
+    fn get_idle_duration(&self) -> Duration {
+        match self.current_step() {
+            Some((ScriptedStep { idle_for: Some(idle_for), .. }, elapsed_in_step)) => *idle_for + elapsed_in_step,
+            _ => Duration::from_millis(0)
+        }
+    }
+
+    fn get_active_window(&self) -> Result<Box<dyn Window>> {
+        let (step, _) = self.current_step().ok_or(anyhow!("ScriptedDesktop timeline finished"))?;
+        if step.window_title.is_none() {
+            bail!("no active window in ScriptedDesktop timeline");
+        }
+        Ok(Box::new(ScriptedWindow { step: step.clone() }))
+    }
+
+    fn before_main_loop_start(&self) -> Result<()> {
+        self.start.set(self.clock.instant());
+        Ok(())
+    }
+
+    fn is_finished(&self) -> bool {
+        !self.loop_ && self.current_step().is_none()
+    }
+}
+
+impl Window for ScriptedWindow {
+    fn get_title(&self) -> Result<String> {
+        Ok(self.step.window_title.clone().unwrap_or_default())
+    }
+
+    fn get_process_id(&self) -> Result<u64> {
+        self.step.process_id.ok_or(anyhow!("ScriptedDesktop step has no process_id"))
+    }
+
+    fn get_process_path(&self) -> Result<PathBuf> {
+        self.step.process_path.clone().ok_or(anyhow!("ScriptedDesktop step has no process_path"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step_error(s: &str) -> String {
+        format!("{:#}", ScriptedStep::from_json(&json::parse(s).unwrap()).unwrap_err())
+    }
+
+    #[test]
+    fn invalid_steps_are_errors() {
+        let step = ScriptedStep::from_json(&json::parse(r#"{"duration_sec": 1.5, "locked": true, "idle_sec": 0}"#).unwrap()).unwrap();
+        assert_eq!(step.duration, Duration::from_millis(1500));
+        assert!(step.locked);
+        assert_eq!(step.idle_for, Some(Duration::ZERO));
+
+        assert_eq!(step_error(r#"{"locked": true}"#), "cannot read duration_sec");
+        assert!(step_error(r#"{"duration_sec": -1}"#).starts_with("invalid duration_sec -1:"));
+        assert!(step_error(r#"{"duration_sec": 1e30}"#).starts_with("invalid duration_sec 1000000000000000000000000000000:"));
+        assert!(step_error(r#"{"duration_sec": 1, "idle_sec": -5}"#).starts_with("invalid idle_sec -5:"));
+        assert_eq!(step_error(r#"{"duration_sec": 1, "locked": "yes"}"#), "locked should be a boolean, not yes");
+        assert_eq!(step_error(r#"{"duration_sec": 1, "window_tile": "a"}"#), r#"window_tile: unknown key, did you mean "window_title"?"#);
+    }
+}
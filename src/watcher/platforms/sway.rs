@@ -0,0 +1,301 @@
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::watcher::core::{Window, Desktop};
+use anyhow::{anyhow, bail, Result};
+use json::JsonValue;
+use x11rb::protocol::xproto::{AtomEnum, ConnectionExt as _};
+use x11rb::rust_connection::RustConnection;
+
+// https://man.archlinux.org/man/sway-ipc.7
+const IPC_MAGIC: &[u8; 6] = b"i3-ipc";
+const IPC_GET_TREE: u32 = 4;
+const IPC_GET_VERSION: u32 = 7;
+const IPC_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Screen lockers whose running process means the session is locked.
+const SCREEN_LOCKERS: [&str; 3] = ["swaylock", "i3lock", "gtklock"];
+
+/// Scanning `/proc` for screen lockers is not free, reuse the result for this long.
+const SCREEN_LOCKER_SCAN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Desktop implementation for sway (Wayland) and i3 (X11), talking to the window manager
+/// over its IPC socket.
+///
+/// i3 does not report the pid of windows, it is read from `_NET_WM_PID` of the X11 window instead
+/// (connecting to `$DISPLAY` on first use).
+pub struct SwayDesktop {
+    socket_path: PathBuf,
+    /// Time and result of the last scan for screen lockers.
+    screen_locker_scan: Mutex<Option<(Instant, bool)>>,
+    x11_conn: Mutex<Option<RustConnection>>,
+}
+pub struct SwayWindow { title: String, app_id: Option<String>, pid: Option<u64> }
+
+impl SwayDesktop {
+    pub fn new(socket_path: PathBuf) -> SwayDesktop {
+        SwayDesktop { socket_path, screen_locker_scan: Mutex::new(None), x11_conn: Mutex::new(None) }
+    }
+
+    /// Use socket from `$SWAYSOCK` or `$I3SOCK`, if either is set.
+    pub fn from_env() -> Option<SwayDesktop> {
+        ["SWAYSOCK", "I3SOCK"].iter()
+            .filter_map(env::var_os)
+            .find(|val| !val.is_empty())
+            .map(|val| SwayDesktop::new(PathBuf::from(val)))
+    }
+
+    pub fn socket_path(&self) -> &Path {
+        self.socket_path.as_path()
+    }
+
+    fn ipc_request(&self, message_type: u32, payload: &[u8]) -> Result<JsonValue> {
+        let mut stream = UnixStream::connect(&self.socket_path)?;
+        stream.set_read_timeout(Some(IPC_TIMEOUT))?;
+        stream.set_write_timeout(Some(IPC_TIMEOUT))?;
+
+        let mut request = Vec::with_capacity(IPC_MAGIC.len() + 8 + payload.len());
+        request.extend_from_slice(IPC_MAGIC);
+        request.extend_from_slice(&(payload.len() as u32).to_ne_bytes());
+        request.extend_from_slice(&message_type.to_ne_bytes());
+        request.extend_from_slice(payload);
+        stream.write_all(&request)?;
+
+        let mut header = [0u8; 14];
+        stream.read_exact(&mut header)?;
+        if &header[..6] != IPC_MAGIC {
+            bail!("IPC reply from {:?} has invalid magic string", self.socket_path);
+        }
+        let reply_length = u32::from_ne_bytes(header[6..10].try_into()?) as usize;
+        let reply_type = u32::from_ne_bytes(header[10..14].try_into()?);
+        if reply_type != message_type {
+            bail!("IPC reply has type {}, expected {}", reply_type, message_type);
+        }
+
+        let mut reply = vec![0u8; reply_length];
+        stream.read_exact(&mut reply)?;
+        Ok(json::parse(std::str::from_utf8(&reply)?)?)
+    }
+
+    /// Read `_NET_WM_PID` of X11 window, for i3 and Xwayland windows in sway.
+    fn get_x11_window_pid(&self, window: u32) -> Result<Option<u64>> {
+        let mut x11_conn = self.x11_conn.lock().unwrap();
+        if x11_conn.is_none() {
+            *x11_conn = Some(x11rb::connect(None)?.0);
+        }
+        let conn = x11_conn.as_ref().unwrap();
+
+        let res = (|| -> Result<Option<u64>> {
+            let net_wm_pid = conn.intern_atom(false, b"_NET_WM_PID")?.reply()?.atom;
+            let reply = conn.get_property(false, window, net_wm_pid, AtomEnum::CARDINAL, 0, 1)?.reply()?;
+            Ok(reply.value32().and_then(|mut it| it.next()).map(u64::from))
+        })();
+        if res.is_err() {
+            // reconnect next time, eg. after the X server restarted
+            *x11_conn = None;
+        }
+        res
+    }
+
+    fn is_screen_locked_cached(&self) -> bool {
+        let mut scan = self.screen_locker_scan.lock().unwrap();
+        match *scan {
+            Some((time, locked)) if time.elapsed() < SCREEN_LOCKER_SCAN_INTERVAL => locked,
+            _ => {
+                let locked = is_screen_locker_running();
+                *scan = Some((Instant::now(), locked));
+                locked
+            }
+        }
+    }
+}
+
+fn find_focused_node(node: &JsonValue) -> Option<&JsonValue> {
+    if node["focused"].as_bool() == Some(true) {
+        return Some(node);
+    }
+
+    node["nodes"].members()
+        .chain(node["floating_nodes"].members())
+        .find_map(find_focused_node)
+}
+
+/// Whether a screen locker runs as the current user (screen lockers of other users' sessions don't count).
+fn is_screen_locker_running() -> bool {
+    let uid = match fs::metadata("/proc/self") {
+        Ok(metadata) => metadata.uid(),
+        Err(_) => return false
+    };
+    let entries = match fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return false
+    };
+
+    entries.flatten()
+        .filter(|entry| entry.metadata().is_ok_and(|tmp| tmp.uid() == uid))
+        .any(|entry| {
+            match fs::read_to_string(entry.path().join("comm")) {
+                Ok(comm) => SCREEN_LOCKERS.contains(&comm.trim()),
+                Err(_) => false
+            }
+        })
+}
+
+impl Desktop for SwayDesktop {
+    fn implementation_name(&self) -> &'static str {
+        "SwayDesktop"
+    }
+
+    fn check_implementation_available(&self) -> Result<()> {
+        match self.ipc_request(IPC_GET_VERSION, b"") {
+            Ok(version) => {
+                println!("Connected to {:?} ({})", self.socket_path, version["human_readable"]);
+                Ok(())
+            }
+            Err(e) => bail!("Cannot talk to sway/i3 IPC socket {:?}: {e}", self.socket_path)
+        }
+    }
+
+    fn is_screen_locked(&self) -> bool {
+        // the IPC does not expose lock state, so look for a running screen locker instead
+        self.is_screen_locked_cached()
+    }
+
+    fn get_idle_duration(&self) -> Duration {
+        // the IPC does not expose idle time
+        Duration::from_millis(0)
+    }
+
+    fn get_active_window(&self) -> Result<Box<dyn Window>> {
+        let tree = self.ipc_request(IPC_GET_TREE, b"")?;
+        let node = find_focused_node(&tree).ok_or(anyhow!("no focused container in sway/i3 tree"))?;
+
+        // i3 and sway with Xwayland windows report X11 class instead of app_id
+        let app_id = node["app_id"].as_str()
+            .or(node["window_properties"]["class"].as_str())
+            .map(String::from);
+        let title = node["name"].as_str()
+            .map(String::from)
+            .or(app_id.clone())
+            .unwrap_or_default();
+        let pid = match (node["pid"].as_u64(), node["window"].as_u32()) {
+            (Some(pid), _) => Some(pid),
+            (None, Some(window)) => self.get_x11_window_pid(window)?,
+            (None, None) => None,
+        };
+
+        Ok(Box::new(SwayWindow { title, app_id, pid }))
+    }
+}
+
+impl SwayWindow {
+    pub fn app_id(&self) -> Option<&str> {
+        self.app_id.as_deref()
+    }
+}
+
+impl Window for SwayWindow {
+    fn get_title(&self) -> Result<String> {
+        Ok(self.title.clone())
+    }
+
+    fn get_process_id(&self) -> Result<u64> {
+        self.pid.ok_or(anyhow!("focused container {:?} has no pid", self.title))
+    }
+
+    fn get_process_path(&self) -> Result<PathBuf> {
+        let pid = self.get_process_id()?;
+        Ok(fs::read_link(format!("/proc/{}/exe", pid))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+    use crate::test_util::TestDir;
+
+    /// Serve `replies` (message type, JSON payload) to consecutive IPC requests on a fresh socket in `dir`.
+    fn fake_ipc_server(dir: &TestDir, replies: Vec<(u32, String)>) -> (PathBuf, thread::JoinHandle<()>) {
+        let socket_path = dir.join("sway.sock");
+        let listener = UnixListener::bind(&socket_path).unwrap();
+
+        let handle = thread::spawn(move || {
+            for (message_type, payload) in replies {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut header = [0u8; 14];
+                stream.read_exact(&mut header).unwrap();
+                assert_eq!(&header[..6], IPC_MAGIC);
+                let request_length = u32::from_ne_bytes(header[6..10].try_into().unwrap()) as usize;
+                assert_eq!(u32::from_ne_bytes(header[10..14].try_into().unwrap()), message_type);
+                stream.read_exact(&mut vec![0u8; request_length]).unwrap();
+
+                let mut reply = Vec::new();
+                reply.extend_from_slice(IPC_MAGIC);
+                reply.extend_from_slice(&(payload.len() as u32).to_ne_bytes());
+                reply.extend_from_slice(&message_type.to_ne_bytes());
+                reply.extend_from_slice(payload.as_bytes());
+                stream.write_all(&reply).unwrap();
+            }
+        });
+
+        (socket_path, handle)
+    }
+
+    #[test]
+    fn reads_focused_window_from_tree() {
+        let pid = std::process::id();
+        let tree = format!(r#"{{"focused": false, "nodes": [
+            {{"focused": false, "name": "eDP-1", "nodes": [
+                {{"focused": false, "name": "editor", "app_id": "code", "pid": 1, "nodes": []}}
+            ], "floating_nodes": [
+                {{"focused": true, "name": "Terminal", "app_id": "foot", "pid": {}, "nodes": []}}
+            ]}}
+        ]}}"#, pid);
+        let dir = TestDir::new("sway-tree");
+        let (socket_path, server) = fake_ipc_server(&dir, vec![
+            (IPC_GET_VERSION, r#"{"human_readable": "sway version 1.8"}"#.to_string()),
+            (IPC_GET_TREE, tree),
+        ]);
+
+        let desktop = SwayDesktop::new(socket_path);
+        desktop.check_implementation_available().unwrap();
+        let window = desktop.get_active_window().unwrap();
+        server.join().unwrap();
+
+        assert_eq!(window.get_title().unwrap(), "Terminal");
+        assert_eq!(window.get_process_id().unwrap(), pid as u64);
+        assert_eq!(window.get_process_path().unwrap(), env::current_exe().unwrap());
+    }
+
+    #[test]
+    fn window_without_name_uses_class() {
+        let tree = r#"{"focused": false, "nodes": [
+            {"focused": true, "name": null, "window_properties": {"class": "XTerm"}, "pid": 1, "nodes": []}
+        ]}"#;
+        let dir = TestDir::new("sway-class");
+        let (socket_path, server) = fake_ipc_server(&dir, vec![(IPC_GET_TREE, tree.to_string())]);
+
+        let window = SwayDesktop::new(socket_path).get_active_window().unwrap();
+        server.join().unwrap();
+
+        assert_eq!(window.get_title().unwrap(), "XTerm");
+    }
+
+    #[test]
+    fn no_focused_window_is_error() {
+        let dir = TestDir::new("sway-unfocused");
+        let (socket_path, server) = fake_ipc_server(&dir, vec![(IPC_GET_TREE, r#"{"focused": false, "nodes": []}"#.to_string())]);
+
+        let res = SwayDesktop::new(socket_path).get_active_window();
+        server.join().unwrap();
+
+        assert!(res.is_err());
+    }
+}
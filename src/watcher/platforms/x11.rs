@@ -0,0 +1,290 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use crate::watcher::core::{Window, Desktop};
+use anyhow::{anyhow, bail, Result};
+use x11rb::connection::{Connection, RequestConnection};
+use x11rb::errors::{ConnectionError, ReplyError};
+use x11rb::protocol::screensaver::{self, ConnectionExt as _};
+use x11rb::protocol::xproto::{Atom, AtomEnum, ConnectionExt as _};
+use x11rb::rust_connection::RustConnection;
+
+/// Desktop implementation for X11 that talks the X protocol directly (no `xdotool`, `xprintidle`).
+///
+/// Needs an EWMH-compliant window manager for `_NET_ACTIVE_WINDOW` and the XScreenSaver
+/// extension for idle time. If the connection breaks (eg. the X server restarted), it is
+/// re-established on the next request.
+pub struct X11Desktop {
+    display: Option<String>,
+    conn: Mutex<Option<X11Connection>>,
+}
+
+struct X11Connection {
+    conn: RustConnection,
+    root: u32,
+    atoms: X11Atoms,
+}
+
+pub struct X11Window {
+    window_id: u32,
+    title: String,
+    class: Option<String>,
+    pid: Option<u32>,
+}
+
+struct X11Atoms {
+    net_active_window: Atom,
+    net_wm_name: Atom,
+    net_wm_pid: Atom,
+    utf8_string: Atom,
+}
+
+impl X11Desktop {
+    /// Connect to the display given by `$DISPLAY`.
+    pub fn connect() -> Result<X11Desktop> {
+        X11Desktop::connect_to(None)
+    }
+
+    /// Connect to given display (eg. `":1"`), or to `$DISPLAY` if `None`.
+    pub fn connect_to(display: Option<&str>) -> Result<X11Desktop> {
+        let conn = X11Connection::connect(display)?;
+        Ok(X11Desktop { display: display.map(String::from), conn: Mutex::new(Some(conn)) })
+    }
+
+    /// Run `f` with the connection, reconnecting first if the previous connection broke.
+    fn with_connection<T>(&self, f: impl FnOnce(&X11Connection) -> Result<T>) -> Result<T> {
+        let mut conn = self.conn.lock().unwrap();
+        if conn.is_none() {
+            *conn = Some(X11Connection::connect(self.display.as_deref())?);
+            println!("Reconnected to X server");
+        }
+
+        let res = f(conn.as_ref().unwrap());
+        if let Err(e) = &res {
+            if is_connection_error(e) {
+                println!("Lost connection to X server: {}", e);
+                *conn = None;
+            }
+        }
+        res
+    }
+
+    /// Whether the screensaver is active. This is not the same as the screen being locked,
+    /// but either way there is nobody at the screen.
+    fn is_screensaver_active(&self) -> bool {
+        self.with_connection(|conn| conn.query_screensaver())
+            .is_ok_and(|info| info.state == u8::from(screensaver::State::ON))
+    }
+}
+
+/// Whether the error means the connection is unusable, as opposed to an error reply
+/// (eg. for a window that is already gone).
+fn is_connection_error(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<ConnectionError>().is_some()
+        || matches!(e.downcast_ref::<ReplyError>(), Some(ReplyError::ConnectionError(_)))
+}
+
+impl X11Connection {
+    fn connect(display: Option<&str>) -> Result<X11Connection> {
+        let (conn, screen_num) = x11rb::connect(display)?;
+        let root = conn.setup().roots[screen_num].root;
+
+        let intern = |name: &[u8]| -> Result<Atom> {
+            Ok(conn.intern_atom(false, name)?.reply()?.atom)
+        };
+        let atoms = X11Atoms {
+            net_active_window: intern(b"_NET_ACTIVE_WINDOW")?,
+            net_wm_name: intern(b"_NET_WM_NAME")?,
+            net_wm_pid: intern(b"_NET_WM_PID")?,
+            utf8_string: intern(b"UTF8_STRING")?,
+        };
+
+        Ok(X11Connection { conn, root, atoms })
+    }
+
+    fn get_property(&self, window: u32, property: Atom, type_: Atom) -> Result<Vec<u8>> {
+        let reply = self.conn.get_property(false, window, property, type_, 0, u32::MAX)?.reply()?;
+        Ok(reply.value)
+    }
+
+    fn get_property_u32(&self, window: u32, property: Atom, type_: Atom) -> Result<Option<u32>> {
+        let reply = self.conn.get_property(false, window, property, type_, 0, 1)?.reply()?;
+        Ok(reply.value32().and_then(|mut it| it.next()))
+    }
+
+    fn get_window_title(&self, window: u32) -> Result<String> {
+        let net_wm_name = self.get_property(window, self.atoms.net_wm_name, self.atoms.utf8_string)?;
+        if !net_wm_name.is_empty() {
+            return Ok(String::from_utf8_lossy(&net_wm_name).into_owned());
+        }
+
+        // legacy WM_NAME, usually Latin-1
+        let wm_name = self.get_property(window, AtomEnum::WM_NAME.into(), AtomEnum::STRING.into())?;
+        Ok(wm_name.iter().map(|&c| c as char).collect())
+    }
+
+    fn get_window_class(&self, window: u32) -> Result<Option<String>> {
+        // WM_CLASS is "instance\0class\0"
+        let wm_class = self.get_property(window, AtomEnum::WM_CLASS.into(), AtomEnum::STRING.into())?;
+        let class = wm_class.split(|&c| c == 0)
+            .filter(|s| !s.is_empty())
+            .nth(1)
+            .map(|s| String::from_utf8_lossy(s).into_owned());
+        Ok(class)
+    }
+
+    fn query_screensaver(&self) -> Result<screensaver::QueryInfoReply> {
+        Ok(self.conn.screensaver_query_info(self.root)?.reply()?)
+    }
+}
+
+impl Desktop for X11Desktop {
+    fn implementation_name(&self) -> &'static str {
+        "X11Desktop"
+    }
+
+    fn check_implementation_available(&self) -> Result<()> {
+        self.with_connection(|conn| {
+            if conn.conn.extension_information(screensaver::X11_EXTENSION_NAME)?.is_none() {
+                bail!("X server does not support the {} extension", screensaver::X11_EXTENSION_NAME);
+            }
+
+            let supports_ewmh = conn.get_property_u32(conn.root, conn.atoms.net_active_window, AtomEnum::WINDOW.into())?;
+            if supports_ewmh.is_none() {
+                bail!("window manager does not set _NET_ACTIVE_WINDOW");
+            }
+
+            Ok(())
+        })
+    }
+
+    fn is_screen_locked(&self) -> bool {
+        // X11 has no notion of a locked screen; screen lockers usually activate the screensaver,
+        // and logind (see `LogindDesktop`) knows about the others
+        self.is_screensaver_active()
+    }
+
+    fn get_idle_duration(&self) -> Duration {
+        match self.with_connection(|conn| conn.query_screensaver()) {
+            Ok(info) => Duration::from_millis(info.ms_since_user_input as u64),
+            Err(_) => Duration::from_millis(0)
+        }
+    }
+
+    fn get_active_window(&self) -> Result<Box<dyn Window>> {
+        self.with_connection(|conn| {
+            let window_id = conn.get_property_u32(conn.root, conn.atoms.net_active_window, AtomEnum::WINDOW.into())?
+                .filter(|&id| id != 0)
+                .ok_or(anyhow!("no active window"))?;
+
+            let title = conn.get_window_title(window_id)?;
+            let class = conn.get_window_class(window_id)?;
+            let pid = conn.get_property_u32(window_id, conn.atoms.net_wm_pid, AtomEnum::CARDINAL.into())?;
+
+            Ok(Box::new(X11Window { window_id, title, class, pid }) as Box<dyn Window>)
+        })
+    }
+}
+
+impl X11Window {
+    pub fn window_id(&self) -> u32 {
+        self.window_id
+    }
+
+    pub fn class(&self) -> Option<&str> {
+        self.class.as_deref()
+    }
+}
+
+impl Window for X11Window {
+    fn get_title(&self) -> Result<String> {
+        Ok(self.title.clone())
+    }
+
+    fn get_process_id(&self) -> Result<u64> {
+        self.pid
+            .map(u64::from)
+            .ok_or(anyhow!("window {:#x} ({:?}) has no _NET_WM_PID", self.window_id, self.class))
+    }
+
+    fn get_process_path(&self) -> Result<PathBuf> {
+        let pid = self.get_process_id()?;
+        Ok(fs::read_link(format!("/proc/{}/exe", pid))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::{Child, Command};
+    use std::thread;
+    use x11rb::COPY_DEPTH_FROM_PARENT;
+    use x11rb::protocol::xproto::{CreateWindowAux, PropMode, WindowClass};
+    use x11rb::wrapper::ConnectionExt as _;
+
+    const DISPLAY: &str = ":97";
+
+    /// Start Xvfb on `DISPLAY`.
+    fn start_xvfb() -> Child {
+        let mut child = Command::new("Xvfb").args([DISPLAY, "-screen", "0", "640x480x24"]).spawn()
+            .expect("cannot start Xvfb");
+        for _ in 0..50 {
+            if x11rb::connect(Some(DISPLAY)).is_ok() {
+                return child;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        let _ = child.kill();
+        let _ = child.wait();
+        panic!("Xvfb did not start on {}", DISPLAY);
+    }
+
+    /// Do what a window manager would: create a window and make it `_NET_ACTIVE_WINDOW`.
+    /// The window lives as long as the returned connection.
+    fn create_active_window(title: &str) -> RustConnection {
+        let (conn, screen_num) = x11rb::connect(Some(DISPLAY)).unwrap();
+        let root = conn.setup().roots[screen_num].root;
+        let intern = |name: &[u8]| conn.intern_atom(false, name).unwrap().reply().unwrap().atom;
+
+        let window = conn.generate_id().unwrap();
+        conn.create_window(COPY_DEPTH_FROM_PARENT, window, root, 0, 0, 100, 100, 0,
+                           WindowClass::INPUT_OUTPUT, 0, &CreateWindowAux::new()).unwrap();
+        conn.change_property8(PropMode::REPLACE, window, intern(b"_NET_WM_NAME"), intern(b"UTF8_STRING"), title.as_bytes()).unwrap();
+        conn.change_property8(PropMode::REPLACE, window, AtomEnum::WM_CLASS, AtomEnum::STRING, b"xterm\0XTerm\0").unwrap();
+        conn.change_property32(PropMode::REPLACE, window, intern(b"_NET_WM_PID"), AtomEnum::CARDINAL, &[std::process::id()]).unwrap();
+        conn.change_property32(PropMode::REPLACE, root, intern(b"_NET_ACTIVE_WINDOW"), AtomEnum::WINDOW, &[window]).unwrap();
+        conn.get_input_focus().unwrap().reply().unwrap();
+        conn
+    }
+
+    #[test]
+    #[ignore = "needs Xvfb, run with --ignored"]
+    fn reads_active_window_and_reconnects() {
+        let mut xvfb = start_xvfb();
+
+        let wm_conn = create_active_window("Terminal é");
+        let desktop = X11Desktop::connect_to(Some(DISPLAY)).unwrap();
+        desktop.check_implementation_available().unwrap();
+        assert!(!desktop.is_screen_locked());
+        assert!(desktop.get_idle_duration() < Duration::from_secs(3600));
+
+        let window = desktop.get_active_window().unwrap();
+        assert_eq!(window.get_title().unwrap(), "Terminal é");
+        assert_eq!(window.get_process_id().unwrap(), std::process::id() as u64);
+        assert_eq!(window.get_process_path().unwrap(), std::env::current_exe().unwrap());
+
+        // restart the X server
+        drop(wm_conn);
+        xvfb.kill().unwrap();
+        xvfb.wait().unwrap();
+        assert!(desktop.get_active_window().is_err());
+        let mut xvfb = start_xvfb();
+
+        let _wm_conn = create_active_window("Editor");
+        assert_eq!(desktop.get_active_window().unwrap().get_title().unwrap(), "Editor");
+
+        xvfb.kill().unwrap();
+        xvfb.wait().unwrap();
+    }
+}
@@ -0,0 +1,521 @@
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, bail, Result};
+use crossbeam_channel::{Receiver, SendTimeoutError, Sender};
+use sha1::{Sha1, Digest};
+use crate::watcher::clock::Clock;
+use crate::watcher::compression::Compression;
+use crate::watcher::config::Config;
+use crate::watcher::core::{coalesce, Event};
+use crate::watcher::encryption::{self, ENCRYPTED_EXTENSION};
+use crate::watcher::journal::Journal;
+
+/// How many messages can be queued for the writer thread; when the queue is full, `WriterThread::push`
+/// keeps events only in the journal.
+const WRITER_QUEUE_CAPACITY: usize = 1024;
+/// How long to wait for room in a full queue before giving up on a message.
+const SEND_TIMEOUT: Duration = Duration::from_secs(60);
+/// First delay before retrying a failed write; it doubles with each failure up to `RETRY_BACKOFF_MAX`.
+const RETRY_BACKOFF_MIN: Duration = Duration::from_secs(5);
+const RETRY_BACKOFF_MAX: Duration = Duration::from_secs(30 * 60);
+/// Attempts to write remaining events when finishing, and delay between them.
+const FINISH_ATTEMPTS: u32 = 3;
+const FINISH_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Part of `Config` that the writer needs.
+#[derive(Debug, Clone)]
+pub struct WriterConfig {
+    pub output_dir: PathBuf,
+    pub compression: Compression,
+    pub encrypt_to: Vec<age::x25519::Recipient>,
+    /// If set, consecutive samples of the same window at most this far apart are written as one span.
+    pub coalesce_max_gap: Option<Duration>,
+}
+
+impl WriterConfig {
+    pub fn from_config(config: &Config) -> WriterConfig {
+        WriterConfig {
+            output_dir: config.output_dir.clone(),
+            compression: config.compression,
+            encrypt_to: config.encrypt_to.clone(),
+            // tolerate some jitter in sampling, but not a missed sample (eg. screen lock or suspend)
+            coalesce_max_gap: config.coalesce.then(|| config.sample_every.mul_f32(1.5)),
+        }
+    }
+}
+
+/// Buffers events and writes them into a new `.jsonl` file in the output dir.
+pub struct MoonwatcherWriter {
+    events_to_write: Vec<Event>,
+    files_written: u64,
+}
+
+impl MoonwatcherWriter {
+    pub fn new() -> MoonwatcherWriter {
+        MoonwatcherWriter {
+            events_to_write: vec![],
+            files_written: 0,
+        }
+    }
+
+    pub fn push(&mut self, e: Event) {
+        self.events_to_write.push(e)
+    }
+
+    pub fn len(&self) -> usize {
+        self.events_to_write.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events_to_write.is_empty()
+    }
+
+    pub fn write(&mut self, config: &WriterConfig, clock: &dyn Clock) -> Result<()> {
+        if self.events_to_write.is_empty() {
+            return Ok(());
+        }
+
+        // ensure output dir
+        if !config.output_dir.exists() {
+            println!("Creating output dir {:?}", config.output_dir);
+            fs::create_dir_all(&config.output_dir)?;
+        }
+
+        // derive name for output file
+        let mut hasher = Sha1::new();
+        hasher.update(whoami::hostname());
+        hasher.update(whoami::username());
+        hasher.update(clock.now().timestamp().to_le_bytes());
+        hasher.update(self.files_written.to_le_bytes());
+        hasher.update(b"moonwatcher");
+        let hasher_result = hasher.finalize();
+        let mut filename = format!("{:02x}.{}", hasher_result, config.compression.extension());
+        if !config.encrypt_to.is_empty() {
+            filename = format!("{}.{}", filename, ENCRYPTED_EXTENSION);
+        }
+        let output_path = config.output_dir.join(filename);
+
+        // serialize everything first, so that failed write keeps all events for retry
+        let spans;
+        let events = match config.coalesce_max_gap {
+            Some(max_gap) => {
+                spans = coalesce(self.events_to_write.iter().cloned(), max_gap);
+                &spans
+            }
+            None => &self.events_to_write,
+        };
+        let mut data = Vec::new();
+        for e in events {
+            data.extend_from_slice(e.to_json().dump().as_bytes());
+            data.push(b'\n');
+        }
+        let mut data = config.compression.compress(&data)?;
+        if !config.encrypt_to.is_empty() {
+            data = encryption::encrypt(&data, &config.encrypt_to)?;
+        }
+
+        if events.len() != self.events_to_write.len() {
+            println!("Writing {} events as {} spans to {:?}", self.events_to_write.len(), events.len(), output_path);
+        } else {
+            println!("Writing {} events to {:?}", self.events_to_write.len(), output_path);
+        }
+        let mut fp = fs::OpenOptions::new().write(true).create_new(true).open(output_path)?;
+        fp.write_all(&data)?;
+        fp.sync_all()?;
+        self.events_to_write.clear();
+        self.files_written += 1;
+
+        Ok(())
+    }
+}
+
+impl Default for MoonwatcherWriter {
+    fn default() -> Self {
+        MoonwatcherWriter::new()
+    }
+}
+
+enum WriterMessage {
+    /// Event, and whether it was appended to the journal.
+    Event(Event, bool),
+    Write,
+    UpdateConfig(WriterConfig),
+    Finish(Sender<Result<()>>),
+}
+
+/// Runs `MoonwatcherWriter` in a separate thread, so that slow writes do not stall sampling.
+///
+/// With a `Journal`, `push` journals each event before passing it on, so that queued events survive
+/// a crash as well; `replayed_events` from the journal are written out first, and events are discarded
+/// from the journal once written. Events are passed over a bounded channel; when the writer falls that
+/// far behind, `push` keeps events only in the journal and the writer reads them back once it caught up.
+/// Without a journal, `push` waits for the writer instead. Failed writes are retried with exponential
+/// backoff, the events are kept until they are written.
+pub struct WriterThread {
+    sender: Sender<WriterMessage>,
+    journal: Option<Arc<Mutex<SharedJournal>>>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+/// Journal and the events at its end that are not queued for the writer thread.
+struct SharedJournal {
+    journal: Journal,
+    /// Number of events at the end of the journal that `push` did not queue because the queue was full.
+    overflowed: usize,
+}
+
+impl WriterThread {
+    pub fn spawn(config: WriterConfig,
+                 journal: Option<Journal>,
+                 replayed_events: Vec<Event>,
+                 clock: Arc<dyn Clock>) -> WriterThread {
+        let (sender, receiver) = crossbeam_channel::bounded(WRITER_QUEUE_CAPACITY);
+        let journal = journal.map(|journal| Arc::new(Mutex::new(SharedJournal { journal, overflowed: 0 })));
+        let journal_ = journal.clone();
+        let handle = thread::Builder::new()
+            .name("moonwatcher-writer".into())
+            .spawn(move || writer_thread_main(receiver, config, journal_, replayed_events, clock))
+            .expect("failed to spawn writer thread");
+
+        WriterThread { sender, journal, handle: Some(handle) }
+    }
+
+    /// Journal event and queue it for writing. If the queue is full, the event is kept only in
+    /// the journal; without a journal, this waits for room in the queue.
+    ///
+    /// Fails if the writer thread is not running anymore; the event is still journaled then.
+    pub fn push(&self, e: Event) -> Result<()> {
+        // the lock keeps the writer from discarding or reading back journal entries between append and send
+        let mut shared = self.journal.as_ref().map(|tmp| tmp.lock().unwrap());
+        let journaled = match &mut shared {
+            Some(shared) => match shared.journal.append(&e) {
+                Ok(_) => true,
+                Err(err) => {
+                    println!("Failed to append event to journal {:?}: {:?}", shared.journal.path(), err);
+                    false
+                }
+            },
+            None => false,
+        };
+
+        if let Some(shared) = &mut shared {
+            // once an event overflowed, later ones follow it, so that the writer gets them in order
+            if journaled && (shared.overflowed > 0 || self.sender.is_full()) {
+                if shared.overflowed == 0 {
+                    println!("Writer queue is full, keeping events in journal {:?} until the writer catches up", shared.journal.path());
+                }
+                shared.overflowed += 1;
+                return Ok(());
+            }
+        }
+        self.send(WriterMessage::Event(e, journaled))
+    }
+
+    /// Ask the writer to write buffered events (without waiting for it).
+    pub fn write(&self) -> Result<()> {
+        self.send(WriterMessage::Write)
+    }
+
+    pub fn update_config(&self, config: WriterConfig) -> Result<()> {
+        self.send(WriterMessage::UpdateConfig(config))
+    }
+
+    /// Write all remaining events and stop the writer thread.
+    pub fn finish(mut self) -> Result<()> {
+        let (result_sender, result_receiver) = crossbeam_channel::bounded(1);
+        self.send(WriterMessage::Finish(result_sender))?;
+        let result = result_receiver.recv()
+            .unwrap_or_else(|_| Err(anyhow!("writer thread exited without writing")));
+
+        if let Some(handle) = self.handle.take() {
+            handle.join().map_err(|_| anyhow!("writer thread panicked"))?;
+        }
+        result
+    }
+
+    /// Send message, waiting for room in the queue, eg. while the writer is busy with a slow write.
+    fn send(&self, msg: WriterMessage) -> Result<()> {
+        match self.sender.send_timeout(msg, SEND_TIMEOUT) {
+            Ok(_) => Ok(()),
+            Err(SendTimeoutError::Timeout(_)) => bail!("writer queue is still full after {:?}", SEND_TIMEOUT),
+            Err(SendTimeoutError::Disconnected(_)) => bail!("writer thread is not running"),
+        }
+    }
+}
+
+fn writer_thread_main(receiver: Receiver<WriterMessage>,
+                      config: WriterConfig,
+                      journal: Option<Arc<Mutex<SharedJournal>>>,
+                      replayed_events: Vec<Event>,
+                      clock: Arc<dyn Clock>) {
+    let mut state = WriterThreadState {
+        writer: MoonwatcherWriter::new(),
+        journaled: 0,
+        config,
+        journal,
+        clock,
+        retry_backoff: RETRY_BACKOFF_MIN,
+        retry_tick_chan: crossbeam_channel::never(),
+    };
+
+    if !replayed_events.is_empty() {
+        println!("Replaying {} events from journal", replayed_events.len());
+        for e in replayed_events {
+            state.push(e, true);
+        }
+        state.try_write();
+    }
+
+    loop {
+        crossbeam_channel::select! {
+            recv(receiver) -> msg => {
+                match msg {
+                    Ok(WriterMessage::Event(e, journaled)) => {
+                        state.push(e, journaled);
+                        state.read_back_overflowed(&receiver);
+                    }
+                    Ok(WriterMessage::Write) => {
+                        state.read_back_overflowed(&receiver);
+                        state.try_write();
+                    }
+                    Ok(WriterMessage::UpdateConfig(new_config)) => state.config = new_config,
+                    Ok(WriterMessage::Finish(result_sender)) => {
+                        state.read_back_overflowed(&receiver);
+                        let _ = result_sender.send(state.finish());
+                        return;
+                    }
+                    Err(_) => {
+                        state.read_back_overflowed(&receiver);
+                        // all senders gone without Finish, still try not to lose anything
+                        if let Err(e) = state.finish() {
+                            println!("{:?}", e);
+                        }
+                        return;
+                    }
+                }
+            }
+            recv(state.retry_tick_chan) -> _ => {
+                state.try_write();
+            }
+        }
+    }
+}
+
+struct WriterThreadState {
+    writer: MoonwatcherWriter,
+    /// How many of the events buffered by `writer` are in the journal; these are its first entries.
+    journaled: usize,
+    config: WriterConfig,
+    journal: Option<Arc<Mutex<SharedJournal>>>,
+    clock: Arc<dyn Clock>,
+    retry_backoff: Duration,
+    retry_tick_chan: Receiver<Instant>,
+}
+
+impl WriterThreadState {
+    fn push(&mut self, e: Event, journaled: bool) {
+        if journaled {
+            self.journaled += 1;
+        }
+        self.writer.push(e);
+    }
+
+    /// Take over events that `push` kept only in the journal, once all queued events are received.
+    fn read_back_overflowed(&mut self, receiver: &Receiver<WriterMessage>) {
+        let Some(shared) = &self.journal else {
+            return;
+        };
+        let mut shared = shared.lock().unwrap();
+        // `push` sends under the lock, so nothing else can be in flight when the queue is empty
+        if shared.overflowed == 0 || !receiver.is_empty() {
+            return;
+        }
+        match shared.journal.read_last(shared.overflowed) {
+            Ok(events) => {
+                println!("Writer caught up, reading back {} events from journal", events.len());
+                self.journaled += events.len();
+                for e in events {
+                    self.writer.push(e);
+                }
+                shared.overflowed = 0;
+            }
+            Err(e) => println!("Failed to read back events from journal {:?}: {:?}", shared.journal.path(), e),
+        }
+    }
+
+    /// Discard written events from the journal; events pushed meanwhile stay there.
+    fn truncate_journal(&mut self) {
+        if let Some(shared) = &self.journal {
+            let mut shared = shared.lock().unwrap();
+            if let Err(e) = shared.journal.discard(self.journaled) {
+                println!("Failed to truncate journal {:?}, events may be written twice: {:?}", shared.journal.path(), e);
+            }
+        }
+        self.journaled = 0;
+    }
+
+    fn try_write(&mut self) {
+        println!("Writing data");
+        match self.writer.write(&self.config, self.clock.as_ref()) {
+            Ok(_) => {
+                println!("Wrote successfully");
+                self.truncate_journal();
+                self.retry_backoff = RETRY_BACKOFF_MIN;
+                self.retry_tick_chan = crossbeam_channel::never();
+            }
+            Err(e) => {
+                println!("Error when writing data (will try again in {:?}): {:?}", self.retry_backoff, e);
+                self.retry_tick_chan = self.clock.tick(self.retry_backoff);
+                self.retry_backoff = (2 * self.retry_backoff).min(RETRY_BACKOFF_MAX);
+            }
+        }
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        println!("Writing data");
+        let mut attempt = 1;
+        loop {
+            match self.writer.write(&self.config, self.clock.as_ref()) {
+                Ok(_) => {
+                    println!("Wrote successfully");
+                    self.truncate_journal();
+                    return Ok(());
+                }
+                Err(e) if attempt >= FINISH_ATTEMPTS => {
+                    match &self.journal {
+                        Some(shared) => bail!("failed to write {} events after {} attempts, they are kept in journal {:?}: {:?}",
+                                              self.writer.len(), attempt, shared.lock().unwrap().journal.path(), e),
+                        None => bail!("failed to write {} events after {} attempts: {:?}", self.writer.len(), attempt, e)
+                    }
+                }
+                Err(e) => {
+                    println!("Error when writing data at exit (attempt {}/{}): {:?}", attempt, FINISH_ATTEMPTS, e);
+                    thread::sleep(FINISH_RETRY_DELAY);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{start_time, TestDir};
+    use crate::watcher::clock::SimulatedClock;
+    use crate::watcher::core::{SessionEvent, SessionEventKind};
+
+    #[test]
+    fn events_are_journaled_on_push_and_discarded_once_written() {
+        let dir = TestDir::new("writer-journal");
+        let journal_path = dir.join("state").join("journal.jsonl");
+        let clock = Arc::new(SimulatedClock::new(start_time(), false));
+        let config = WriterConfig {
+            output_dir: dir.join("log"),
+            compression: Compression::None,
+            encrypt_to: vec![],
+            coalesce_max_gap: None,
+        };
+
+        let (journal, replayed) = Journal::open(&journal_path).unwrap();
+        let writer = WriterThread::spawn(config, Some(journal), replayed, clock.clone());
+        for _ in 0..3 {
+            writer.push(Event::Session(SessionEvent::new(clock.as_ref(), SessionEventKind::Lock))).unwrap();
+        }
+        // journaled right away, whether or not the writer thread got to them
+        assert_eq!(Journal::open(&journal_path).unwrap().1.len(), 3);
+
+        writer.finish().unwrap();
+        assert!(Journal::open(&journal_path).unwrap().1.is_empty());
+        let written: Vec<_> = fs::read_dir(dir.join("log")).unwrap().collect();
+        assert_eq!(written.len(), 1);
+        let data = fs::read_to_string(written[0].as_ref().unwrap().path()).unwrap();
+        assert_eq!(data.lines().count(), 3);
+    }
+
+    #[test]
+    fn events_overflowing_the_queue_are_kept_in_journal() {
+        let dir = TestDir::new("writer-overflow");
+        let journal_path = dir.join("state").join("journal.jsonl");
+        let clock = Arc::new(SimulatedClock::new(start_time(), false));
+        let config = WriterConfig {
+            output_dir: dir.join("log"),
+            compression: Compression::None,
+            encrypt_to: vec![],
+            coalesce_max_gap: None,
+        };
+
+        // a writer thread that did not start reading its queue yet
+        let (journal, replayed) = Journal::open(&journal_path).unwrap();
+        let (sender, receiver) = crossbeam_channel::bounded(2);
+        let journal = Some(Arc::new(Mutex::new(SharedJournal { journal, overflowed: 0 })));
+        let mut writer = WriterThread { sender, journal: journal.clone(), handle: None };
+        for _ in 0..5 {
+            writer.push(Event::Session(SessionEvent::new(clock.as_ref(), SessionEventKind::Lock))).unwrap();
+            clock.advance(Duration::from_secs(1));
+        }
+        assert_eq!(Journal::open(&journal_path).unwrap().1.len(), 5);
+        assert_eq!(journal.as_ref().unwrap().lock().unwrap().overflowed, 3);
+
+        let clock_ = clock.clone();
+        writer.handle = Some(thread::spawn(move || writer_thread_main(receiver, config, journal, replayed, clock_)));
+        writer.finish().unwrap();
+        assert!(Journal::open(&journal_path).unwrap().1.is_empty());
+        let written: Vec<_> = fs::read_dir(dir.join("log")).unwrap().collect();
+        assert_eq!(written.len(), 1);
+        let data = fs::read_to_string(written[0].as_ref().unwrap().path()).unwrap();
+        let times: Vec<_> = data.lines().map(|line| Event::from_json(&json::parse(line).unwrap()).unwrap().time()).collect();
+        let expected: Vec<_> = (0..5).map(|i| start_time() + chrono::Duration::seconds(i)).collect();
+        assert_eq!(times, expected);
+    }
+
+    #[test]
+    fn failed_writes_are_retried_with_backoff() {
+        let dir = TestDir::new("writer-retry");
+        let clock = Arc::new(SimulatedClock::new(start_time(), false));
+        // a file where the output dir should be
+        let output_dir = dir.write("log", "");
+        let mut state = WriterThreadState {
+            writer: MoonwatcherWriter::new(),
+            journaled: 0,
+            config: WriterConfig {
+                output_dir: output_dir.clone(),
+                compression: Compression::None,
+                encrypt_to: vec![],
+                coalesce_max_gap: None,
+            },
+            journal: None,
+            clock: clock.clone(),
+            retry_backoff: RETRY_BACKOFF_MIN,
+            retry_tick_chan: crossbeam_channel::never(),
+        };
+        state.push(Event::Session(SessionEvent::new(clock.as_ref(), SessionEventKind::Lock)), false);
+        state.try_write();
+
+        let mut backoffs = Vec::new();
+        for _ in 0..10 {
+            let retry_start = clock.elapsed();
+            while state.retry_tick_chan.try_recv().is_err() {
+                clock.advance(Duration::from_secs(1));
+            }
+            backoffs.push((clock.elapsed() - retry_start).as_secs());
+            state.try_write();
+        }
+        assert_eq!(backoffs, vec![5, 10, 20, 40, 80, 160, 320, 640, 1280, 1800]);
+        assert_eq!(state.writer.len(), 1);
+
+        fs::remove_file(&output_dir).unwrap();
+        while state.retry_tick_chan.try_recv().is_err() {
+            clock.advance(Duration::from_secs(1));
+        }
+        state.try_write();
+        assert!(state.writer.is_empty());
+        assert_eq!(state.retry_backoff, RETRY_BACKOFF_MIN);
+        assert!(state.retry_tick_chan.try_recv().is_err());
+        assert_eq!(fs::read_dir(&output_dir).unwrap().count(), 1);
+    }
+}
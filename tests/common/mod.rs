@@ -0,0 +1,85 @@
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+use moonwatch_rs::store::{EventFilter, EventStore};
+use moonwatch_rs::watcher::core::Event;
+
+/// Fresh directory for one test, removed when dropped.
+pub struct TestDir {
+    path: PathBuf,
+}
+
+impl TestDir {
+    pub fn new(name: &str) -> TestDir {
+        let path = std::env::temp_dir().join(format!("moonwatch-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TestDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    pub fn write(&self, name: &str, contents: &str) -> PathBuf {
+        let path = self.path.join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Run `moonwatcher` on a scripted timeline and simulated clock until the timeline ends.
+pub fn run_moonwatcher(config_path: &Path, timeline_path: &Path) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_moonwatcher"))
+        .arg(config_path)
+        .arg("--scripted-desktop").arg(timeline_path)
+        .arg("--simulated-clock").arg("2024-01-01T08:00:00Z")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "moonwatcher failed: {}", String::from_utf8_lossy(&output.stderr));
+    output
+}
+
+/// Run `moonwatch` with given arguments in UTC, so that days in reports do not depend on the local time zone.
+pub fn run_moonwatch(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_moonwatch"))
+        .args(args)
+        .env("TZ", "UTC")
+        .output()
+        .unwrap()
+}
+
+/// Start `moonwatcher` on a scripted timeline and the system clock, eg. to change its config while it runs.
+pub fn spawn_moonwatcher(config_path: &Path, timeline_path: &Path) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_moonwatcher"))
+        .arg(config_path)
+        .arg("--scripted-desktop").arg(timeline_path)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap()
+}
+
+pub fn read_events(output_dir: &Path) -> Vec<Event> {
+    EventStore::open(output_dir).unwrap()
+        .events(EventFilter::default()).unwrap()
+        .collect::<anyhow::Result<_>>().unwrap()
+}
+
+/// Event type names, with window title for `ActiveWindowEvent`s, eg. `["DaemonStart", "ActiveWindowEvent a", ...]`.
+pub fn describe(events: &[Event]) -> Vec<String> {
+    events.iter()
+        .map(|e| match e {
+            Event::ActiveWindow(e) => format!("ActiveWindowEvent {}", e.window_title),
+            Event::Session(e) => e.kind.type_name().to_string(),
+        })
+        .collect()
+}
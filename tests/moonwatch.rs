@@ -0,0 +1,81 @@
+mod common;
+
+use std::path::{Path, PathBuf};
+use age::secrecy::ExposeSecret;
+use common::{run_moonwatch, run_moonwatcher, TestDir};
+
+const TIMELINE: &str = r#"{"steps": [
+    {"duration_sec": 150, "window_title": "Inbox", "process_path": "/usr/bin/thunderbird"},
+    {"duration_sec": 150, "window_title": "Editor", "process_path": "/usr/bin/code"},
+    {"duration_sec": 150, "window_title": "Editor", "process_path": "/usr/bin/code", "idle_sec": 400}
+]}"#;
+
+/// Record `TIMELINE` (samples every minute: 2 of mail, 2 of editor and 3 away from it) to `log` in `dir`.
+fn record(dir: &TestDir, main: &str) -> PathBuf {
+    let config_path = dir.write("config.json", &format!(r#"{{
+        "main": {{"output_dir": "log", "sample_every_sec": 60, "write_every_sec": 3600, "state_dir": "state",
+                 "afk_after_sec": 300, "reload_on_change": false{}}},
+        "tags": {{"mail": {{"process_name": "^thunderbird$"}}}}
+    }}"#, main));
+    let timeline_path = dir.write("timeline.json", TIMELINE);
+    run_moonwatcher(&config_path, &timeline_path);
+    dir.path().join("log")
+}
+
+fn report(output_dir: &Path, args: &[&str]) -> String {
+    let mut all_args = vec!["report", output_dir.to_str().unwrap()];
+    all_args.extend(args);
+    let output = run_moonwatch(&all_args);
+    assert!(output.status.success(), "moonwatch failed: {}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn reports_time_per_tag_and_process() {
+    let dir = TestDir::new("report");
+    let output_dir = record(&dir, "");
+
+    assert_eq!(report(&output_dir, &[]), "\
+Total
+  (untagged)     0h 02m   50.0%
+  mail           0h 02m   50.0%
+  total          0h 04m
+
+");
+    assert_eq!(report(&output_dir, &["--by", "process", "--include-afk"]), "\
+Total
+  code            0h 05m   71.4%
+  thunderbird     0h 02m   28.6%
+  total           0h 07m
+
+");
+    assert_eq!(report(&output_dir, &["--per", "day"]).lines().next(), Some("2024-01-01 Mon"));
+    assert_eq!(report(&output_dir, &["--per", "week"]).lines().next(), Some("2024-W01 (from 2024-01-01)"));
+}
+
+#[test]
+fn filters_by_day_and_host() {
+    let dir = TestDir::new("report-filter");
+    let output_dir = record(&dir, "");
+
+    let no_events = format!("No events found in {:?}\n", output_dir);
+    assert!(report(&output_dir, &["--from", "2024-01-01", "--to", "2024-01-01"]).contains("total          0h 04m"));
+    assert_eq!(report(&output_dir, &["--from", "2024-01-02"]), no_events);
+    assert_eq!(report(&output_dir, &["--to", "2023-12-31"]), no_events);
+    assert_eq!(report(&output_dir, &["--host", "no-such-host"]), no_events);
+}
+
+#[test]
+fn reads_compressed_and_encrypted_logs() {
+    let key = age::x25519::Identity::generate();
+    let dir = TestDir::new("report-encrypted");
+    let output_dir = record(&dir, &format!(r#", "compression": "zstd", "encrypt_to": "{}""#, key.to_public()));
+    let identity_path = dir.write("identity.txt", key.to_string().expose_secret());
+
+    let report = report(&output_dir, &["--identity", identity_path.to_str().unwrap()]);
+    assert!(report.contains("total          0h 04m"), "{}", report);
+
+    let output = run_moonwatch(&["report", output_dir.to_str().unwrap()]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("a private key is needed"));
+}
@@ -0,0 +1,181 @@
+mod common;
+
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+use moonwatch_rs::watcher::core::{Event, EventStatus, SessionEventKind};
+use common::{describe, read_events, run_moonwatcher, spawn_moonwatcher, TestDir};
+
+const CONFIG: &str = r#"{
+    "main": {"output_dir": "log", "sample_every_sec": 60, "write_every_sec": 3600, "path_to_base_config": null,
+             "compression": "none", "state_dir": "state", "afk_after_sec": 300, "reload_on_change": false},
+    "tags": {"mail": {"process_name": "^thunderbird$"}},
+    "ignore": {"window_title": "^Private"}
+}"#;
+
+fn window_events(events: &[Event]) -> Vec<&moonwatch_rs::watcher::core::ActiveWindowEvent> {
+    events.iter()
+        .filter_map(|e| match e {
+            Event::ActiveWindow(e) => Some(e),
+            Event::Session(_) => None,
+        })
+        .collect()
+}
+
+#[test]
+fn records_scripted_timeline() {
+    let dir = TestDir::new("timeline");
+    let config_path = dir.write("config.json", CONFIG);
+    let timeline_path = dir.write("timeline.json", r#"{"steps": [
+        {"duration_sec": 150, "window_title": "Inbox", "process_path": "/usr/bin/thunderbird"},
+        {"duration_sec": 120, "window_title": "Private window", "process_path": "/usr/bin/firefox"},
+        {"duration_sec": 120, "window_title": "Editor", "process_path": "/usr/bin/code"}
+    ]}"#);
+
+    run_moonwatcher(&config_path, &timeline_path);
+    let events = read_events(&dir.path().join("log"));
+
+    assert_eq!(describe(&events), vec![
+        "DaemonStart",
+        "ActiveWindowEvent Inbox",
+        "ActiveWindowEvent Inbox",
+        "ActiveWindowEvent Editor",
+        "ActiveWindowEvent Editor",
+        "DaemonStop",
+    ]);
+
+    let windows = window_events(&events);
+    assert_eq!(windows[0].time.to_rfc3339(), "2024-01-01T08:01:00+00:00");
+    assert_eq!(windows[0].process_path, PathBuf::from("/usr/bin/thunderbird"));
+    assert!(windows[0].tags.contains(&"mail".to_string()));
+    assert!(windows[2].tags.is_empty());
+    assert!(windows.iter().all(|e| e.duration == Duration::from_secs(60)));
+    assert!(windows.iter().all(|e| e.status == EventStatus::Active));
+}
+
+#[test]
+fn records_lock_and_afk() {
+    let dir = TestDir::new("lock");
+    let config_path = dir.write("config.json", CONFIG);
+    let timeline_path = dir.write("timeline.json", r#"{"steps": [
+        {"duration_sec": 420, "window_title": "Editor", "process_path": "/usr/bin/code", "idle_sec": 0},
+        {"duration_sec": 600, "locked": true},
+        {"duration_sec": 1200, "window_title": "Editor", "process_path": "/usr/bin/code"}
+    ]}"#);
+
+    run_moonwatcher(&config_path, &timeline_path);
+    let events = read_events(&dir.path().join("log"));
+    let described = describe(&events);
+
+    let position = |name: &str| described.iter().position(|tmp| tmp == name).unwrap_or_else(|| panic!("no {} in {:?}", name, described));
+    assert!(position("AfkStart") < position("AfkEnd"));
+    assert!(position("AfkEnd") < position("LockEvent"), "afk should end when the screen locks: {:?}", described);
+    assert!(position("LockEvent") < position("UnlockEvent"));
+    assert_eq!(described.first().unwrap(), "DaemonStart");
+    assert_eq!(described.last().unwrap(), "DaemonStop");
+
+    let windows = window_events(&events);
+    let afk = windows.iter().filter(|e| e.status == EventStatus::Afk).count();
+    assert_eq!(afk, 2, "samples at 5 and 6 minutes idle should be afk");
+
+    // the time before the first sample after the unlock was spent locked, so it counts one sample period at most
+    let after_unlock = window_events(&events[position("UnlockEvent")..]);
+    assert!(after_unlock.iter().all(|e| e.duration == Duration::from_secs(60)));
+    let total: Duration = windows.iter().map(|e| e.duration).sum();
+    assert!(total <= Duration::from_secs(420 + 1200), "{:?} counted, though only 27 minutes were unlocked", total);
+}
+
+#[test]
+fn keeps_titles_out_of_output_when_encrypting() {
+    let dir = TestDir::new("redact-log");
+    let recipient = age::x25519::Identity::generate().to_public();
+    let config = CONFIG.replace(r#""compression": "none","#, &format!(r#""compression": "none", "encrypt_to": "{}","#, recipient));
+    let config_path = dir.write("config.json", &config);
+    let timeline_path = dir.write("timeline.json", r#"{"steps": [
+        {"duration_sec": 150, "window_title": "Inbox", "process_path": "/usr/bin/thunderbird"},
+        {"duration_sec": 120, "window_title": "Private window", "process_path": "/usr/bin/firefox"}
+    ]}"#);
+
+    let output = run_moonwatcher(&config_path, &timeline_path);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Recording ActiveWindowEvent at 2024-01-01T08:01:00+00:00"), "{}", stdout);
+    assert!(stdout.contains("Ignoring ActiveWindowEvent at"), "{}", stdout);
+    for plaintext in ["Inbox", "/usr/bin/thunderbird", "Private window", "/usr/bin/firefox"] {
+        assert!(!stdout.contains(plaintext), "{:?} in output: {}", plaintext, stdout);
+    }
+}
+
+
+#[test]
+fn check_config_accepts_config() {
+    let dir = TestDir::new("check-config");
+    let config_path = dir.write("config.json", CONFIG);
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_moonwatcher"))
+        .arg("check-config").arg(&config_path)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("is OK"));
+
+    let bad_config_path = dir.write("bad.json", r#"{"main": {"output_dir": "log", "sample_every_sec": 60, "write_every_sec": 3600, "path_to_base_config": null, "sample_evry_sec": 1}}"#);
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_moonwatcher"))
+        .arg("check-config").arg(&bad_config_path)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
+
+#[test]
+fn reload_keeps_scripted_timeline_running() {
+    let dir = TestDir::new("reload");
+    let config = |tags: &str| format!(r#"{{
+        "main": {{"output_dir": "log", "sample_every_sec": 0.1, "write_every_sec": 0.5, "compression": "none",
+                 "state_dir": "state", "reload_on_change": true}},
+        "tags": {}
+    }}"#, tags);
+    let config_path = dir.write("config.json", &config("null"));
+    let timeline_path = dir.write("timeline.json", r#"{"steps": [
+        {"duration_sec": 1, "window_title": "First", "process_path": "/usr/bin/code"},
+        {"duration_sec": 4, "window_title": "Second", "process_path": "/usr/bin/code"}
+    ]}"#);
+
+    let child = spawn_moonwatcher(&config_path, &timeline_path);
+    // change config once the daemon is sampling (and so watching the config)
+    let journal_path = dir.path().join("state").join("journal.jsonl");
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while fs::metadata(&journal_path).map(|m| m.len()).unwrap_or(0) == 0 {
+        assert!(Instant::now() < deadline, "moonwatcher did not start sampling");
+        thread::sleep(Duration::from_millis(10));
+    }
+    dir.write("config.json", &config(r#"{"reloaded": {"window_title": "."}}"#));
+
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "moonwatcher failed: {}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.matches("Reloading configuration file").count(), 1, "{}", stdout);
+
+    let events = read_events(&dir.path().join("log"));
+    let config_hashes: Vec<(&str, &str)> = events.iter()
+        .filter_map(|e| match e {
+            Event::Session(e) => match &e.kind {
+                SessionEventKind::DaemonStart(info) => Some(("DaemonStart", info.config_hash.as_str())),
+                SessionEventKind::ConfigReload(info) => Some(("ConfigReload", info.config_hash.as_str())),
+                SessionEventKind::DaemonStop(info) => Some(("DaemonStop", info.config_hash.as_str())),
+                _ => None,
+            },
+            Event::ActiveWindow(_) => None,
+        })
+        .collect();
+    assert_eq!(config_hashes.iter().map(|(name, _)| *name).collect::<Vec<_>>(), vec!["DaemonStart", "ConfigReload", "DaemonStop"]);
+    assert_ne!(config_hashes[0].1, config_hashes[1].1);
+    assert_eq!(config_hashes[1].1, config_hashes[2].1);
+
+    let windows = window_events(&events);
+    let first_reloaded = windows.iter().position(|e| e.tags.contains(&"reloaded".to_string()))
+        .expect("no events were recorded with the reloaded config");
+    assert!(windows[..first_reloaded].iter().any(|e| e.window_title == "First"));
+    // the timeline went on rather than starting over
+    assert!(windows[first_reloaded..].iter().all(|e| e.window_title == "Second" && !e.tags.is_empty()));
+}